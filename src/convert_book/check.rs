@@ -0,0 +1,77 @@
+//! Check the links in a book without rendering it
+
+use std::error::Error;
+use std::path::Path;
+
+use helpers;
+use helpers::check_links::{check_links, BrokenLink};
+use helpers::normalize_code_blocks::HiddenCode;
+use helpers::raw_html::translate_html;
+use convert_book::{Book, print_unknown_html};
+
+/// Read the URL prefixes we don't need to audit from a file with one prefix
+/// per line (lines starting with `#` are comments).
+fn read_allowlist(path: &str) -> Result<Vec<String>, Box<Error>> {
+    let content = try!(helpers::file::get_file_content(path));
+
+    Ok(content.lines()
+              .map(str::trim)
+              .filter(|line| !line.is_empty() && !line.starts_with('#'))
+              .map(str::to_owned)
+              .collect())
+}
+
+/// Check the book's internal links and list its external ones and the raw
+/// HTML that is dropped from the PDF.
+///
+/// Returns whether all internal links are fine.
+pub fn check_book(src_path: &Path, meta_file: &str, allowlist: Option<&str>)
+    -> Result<bool, Box<Error>>
+{
+    let book = try!(try!(Book::load(src_path, meta_file)).to_markdown(HiddenCode::Remove));
+    let allowlist = match allowlist {
+        Some(path) => try!(read_allowlist(path)),
+        None => vec![],
+    };
+
+    let report = check_links(&book);
+
+    for broken in &report.broken {
+        match *broken {
+            BrokenLink::MissingAnchor { ref section, ref target } => {
+                println!("[✗] {}: Link to missing anchor `#{}`", section, target);
+            }
+            BrokenLink::MissingReference { ref section, ref id } => {
+                println!("[✗] {}: Reference `[{}]` is not defined", section, id);
+            }
+            BrokenLink::RelativeLink { ref section, ref target } => {
+                println!("[✗] {}: Relative link `{}` is not part of the book", section, target);
+            }
+        }
+    }
+
+    let (_, unknown_html) = translate_html(&book);
+    print_unknown_html(&unknown_html);
+
+    let unchecked: Vec<(&String, &usize)> = report.external
+        .iter()
+        .filter(|&(url, _)| !allowlist.iter().any(|prefix| url.starts_with(prefix)))
+        .collect();
+
+    let allowlisted = report.external.len() - unchecked.len();
+
+    if !unchecked.is_empty() {
+        println!("\nExternal links:");
+        for (url, count) in unchecked {
+            println!("{:>4}× {}", count, url);
+        }
+    }
+
+    println!("\n[{}] {} broken links, {} external links ({} allowlisted)",
+             if report.broken.is_empty() { "✓" } else { "✗" },
+             report.broken.len(),
+             report.external.len(),
+             allowlisted);
+
+    Ok(report.broken.is_empty())
+}
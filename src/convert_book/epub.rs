@@ -0,0 +1,531 @@
+//! Finish pandoc's EPUB 3
+//!
+//! Pandoc leaves out some of the semantics e-readers use, so we open the EPUB
+//! again and
+//!
+//! - add `epub:type`s to the chapters, the glossary and the index,
+//! - turn the footnotes into `<aside epub:type="footnote">`s next to their
+//!   references, which readers show as popups instead of jumping away,
+//! - add landmarks and a page list matching the PDF to the navigation and
+//! - embed (and optionally obfuscate) a font for the code.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process::Command;
+use regex::{Regex, Captures};
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use zip::write::FileOptions;
+use sha1::{Digest, Sha1};
+
+use convert_book::localization::Language;
+use convert_book::page_map::PageMap;
+
+/// Number of bytes at the start of a font file the obfuscation changes
+const OBFUSCATED_BYTES: usize = 1040;
+
+/// A file of a font family, e.g. the bold one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FontFile {
+    pub path: String,
+    pub bold: bool,
+    pub italic: bool,
+}
+
+/// A font family to embed in the EPUB
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbeddedFont {
+    pub family: String,
+    pub files: Vec<FontFile>,
+    /// Obfuscate the font files like the EPUB specification describes, which
+    /// some font licenses require
+    pub obfuscate: bool,
+}
+
+impl EmbeddedFont {
+    /// Find the files of an installed font family with fontconfig
+    pub fn find(family: &str, obfuscate: bool) -> Result<EmbeddedFont, Box<Error>> {
+        let mut files: Vec<FontFile> = vec![];
+
+        for &(style, bold, italic) in &[("Regular", false, false), ("Bold", true, false),
+                                        ("Oblique", false, true), ("Bold Oblique", true, true)] {
+            let output = try!(Command::new("fc-match")
+                                  .arg("--format=%{family}\n%{file}")
+                                  .arg(format!("{}:style={}", family, style))
+                                  .output());
+            let output = String::from_utf8_lossy(&output.stdout);
+            let mut lines = output.lines();
+            let found = lines.next().unwrap_or("");
+            let path = lines.next().unwrap_or("");
+
+            // fontconfig falls back to other fonts
+            if !found.to_lowercase().contains(&family.to_lowercase()) || path.is_empty() {
+                if bold || italic {
+                    continue;
+                }
+                return Err(From::from(format!("Font `{}` is not installed", family)));
+            }
+            if !files.iter().any(|f| f.path == path) {
+                files.push(FontFile { path: path.to_owned(), bold: bold, italic: italic });
+            }
+        }
+
+        Ok(EmbeddedFont { family: family.to_owned(), files: files, obfuscate: obfuscate })
+    }
+}
+
+/// What to add to the EPUB
+pub struct EpubSettings<'a> {
+    pub font: Option<&'a EmbeddedFont>,
+    pub page_map: Option<&'a PageMap>,
+    /// IDs of the glossary and index sections
+    pub glossary: Option<&'a str>,
+    pub index: Option<&'a str>,
+    /// For the titles of the landmarks
+    pub language: &'a Language,
+}
+
+/// A file in the EPUB container
+pub struct Entry {
+    pub name: String,
+    pub data: Vec<u8>,
+    /// Whether the file is uncompressed
+    pub stored: bool,
+}
+
+/// The files of the EPUB at `path`, in the order of the container
+pub fn read_entries(path: &str) -> Result<Vec<Entry>, Box<Error>> {
+    let mut archive = try!(ZipArchive::new(try!(File::open(path))));
+    let mut entries = vec![];
+
+    for index in 0..archive.len() {
+        let mut file = try!(archive.by_index(index));
+        let mut data = vec![];
+        try!(file.read_to_end(&mut data));
+        let stored = file.compression() == CompressionMethod::Stored;
+        entries.push(Entry { name: file.name().to_owned(), data: data, stored: stored });
+    }
+
+    Ok(entries)
+}
+
+/// Write the EPUB, the `mimetype` needs to be the first and uncompressed file
+pub fn write_entries(path: &str, entries: &[Entry]) -> Result<(), Box<Error>> {
+    let mut zip = ZipWriter::new(try!(File::create(path)));
+    let mimetype = entries.iter().filter(|e| e.name == "mimetype");
+    let others = entries.iter().filter(|e| e.name != "mimetype");
+
+    for entry in mimetype.chain(others) {
+        let method = if entry.name == "mimetype" {
+            CompressionMethod::Stored
+        } else {
+            CompressionMethod::Deflated
+        };
+        try!(zip.start_file(entry.name.clone(), FileOptions::default().compression_method(method)));
+        try!(zip.write_all(&entry.data));
+    }
+
+    try!(zip.finish());
+    Ok(())
+}
+
+/// The directory of a path in the container, with a trailing slash
+pub fn parent(path: &str) -> &str {
+    path.rfind('/').map_or("", |index| &path[..index + 1])
+}
+
+/// The container path of `href`, relative to the file `base`
+pub fn resolve(base: &str, href: &str) -> String {
+    let mut parts: Vec<&str> = parent(base).split('/').filter(|p| !p.is_empty()).collect();
+    for part in href.split('/') {
+        match part {
+            ".." => {
+                parts.pop();
+            }
+            "." | "" => {}
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// The href of the container path `to` in the file `from`
+fn relative(from: &str, to: &str) -> String {
+    let from: Vec<&str> = parent(from).split('/').filter(|p| !p.is_empty()).collect();
+    let to: Vec<&str> = to.split('/').collect();
+    let common = from.iter().zip(&to[..to.len() - 1]).take_while(|&(a, b)| a == b).count();
+
+    let mut parts = vec![".."; from.len() - common];
+    parts.extend(&to[common..]);
+    parts.join("/")
+}
+
+pub fn text(entry: &Entry) -> String {
+    String::from_utf8_lossy(&entry.data).into_owned()
+}
+
+pub fn attribute(tag: &str, name: &str) -> Option<String> {
+    let pattern = Regex::new(&format!(r#"\s{}="([^"]*)""#, name)).unwrap();
+    pattern.captures(tag).and_then(|caps| caps.at(1)).map(str::to_owned)
+}
+
+/// An item of the OPF manifest
+pub struct Item {
+    pub id: String,
+    /// Path in the container
+    pub path: String,
+    pub media_type: String,
+    pub properties: String,
+}
+
+pub fn manifest_items(opf: &str, opf_path: &str) -> Vec<Item> {
+    let item = Regex::new(r"<item\s[^>]*>").unwrap();
+    item.find_iter(opf)
+        .map(|(start, end)| {
+            let tag = &opf[start..end];
+            Item {
+                id: attribute(tag, "id").unwrap_or(String::new()),
+                path: resolve(opf_path, &attribute(tag, "href").unwrap_or(String::new())),
+                media_type: attribute(tag, "media-type").unwrap_or(String::new()),
+                properties: attribute(tag, "properties").unwrap_or(String::new()),
+            }
+        })
+        .collect()
+}
+
+/// Path of the OPF file, from `META-INF/container.xml`
+pub fn opf_path(entries: &[Entry]) -> Option<String> {
+    let full_path = Regex::new(r#"full-path="(?P<path>[^"]+)""#).unwrap();
+    let container = match entries.iter().find(|e| e.name == "META-INF/container.xml") {
+        Some(container) => text(container),
+        None => return None,
+    };
+    full_path.captures(&container).and_then(|caps| caps.name("path")).map(str::to_owned)
+}
+
+/// Paths of the spine's documents, in reading order
+pub fn spine(opf: &str, items: &[Item]) -> Vec<String> {
+    let itemref = Regex::new(r#"<itemref\s[^>]*idref="(?P<id>[^"]+)""#).unwrap();
+    itemref.captures_iter(opf)
+           .filter_map(|caps| items.iter().find(|item| item.id == caps.name("id").unwrap()))
+           .map(|item| item.path.clone())
+           .collect()
+}
+
+/// Set the `epub:type` of the top-level sections
+fn add_section_types(xhtml: &str, glossary: Option<&str>, index: Option<&str>) -> String {
+    let section = Regex::new(r#"<section(?P<attrs>\s[^>]*\bclass="([^"]*\s)?level1(\s[^"]*)?"[^>]*)>"#).unwrap();
+    let epub_type = Regex::new(r#"\sepub:type="[^"]*""#).unwrap();
+
+    section.replace_all(xhtml, |caps: &Captures| {
+        let attrs = caps.name("attrs").unwrap();
+        let id = attribute(attrs, "id");
+        let section_type = match id {
+            Some(ref id) if Some(&id[..]) == glossary => "glossary",
+            Some(ref id) if Some(&id[..]) == index => "index",
+            _ => "chapter",
+        };
+        format!("<section{} epub:type=\"{}\">", epub_type.replace_all(attrs, ""), section_type)
+    })
+}
+
+/// The `<li>` items of a HTML list with their IDs and contents
+fn list_items(html: &str) -> Vec<(String, String)> {
+    let tag = Regex::new(r"</?li\b[^>]*>").unwrap();
+    let mut items = vec![];
+    let mut depth = 0;
+    let mut current: Option<(String, usize)> = None;
+
+    for (start, end) in tag.find_iter(html) {
+        if html[start..].starts_with("</") {
+            depth -= 1;
+            if depth == 0 {
+                if let Some((id, content_start)) = current.take() {
+                    items.push((id, html[content_start..start].to_owned()));
+                }
+            }
+        } else {
+            if depth == 0 {
+                current = attribute(&html[start..end], "id").map(|id| (id, end));
+            }
+            depth += 1;
+        }
+    }
+
+    items
+}
+
+/// Remove the footnotes sections of a document and return their notes
+fn take_footnotes(xhtml: &str) -> (String, Vec<(String, String)>) {
+    let footnotes = Regex::new(r#"(?s)<section\s[^>]*class="footnotes[^"]*"[^>]*>(?P<notes>.*?)</section>"#).unwrap();
+    let mut notes = vec![];
+
+    let without = footnotes.replace_all(xhtml, |caps: &Captures| {
+        notes.extend(list_items(caps.name("notes").unwrap()));
+        String::new()
+    });
+    (without, notes)
+}
+
+/// Point the note references of a document to `<aside>`s at its end
+fn place_footnotes(xhtml: &str, notes: &BTreeMap<String, String>) -> String {
+    let reference = Regex::new(r#"<a(?P<attrs>\s[^>]*\bclass="footnote(Ref|-ref)"[^>]*)>"#).unwrap();
+    let href = Regex::new(r#"\shref="[^"]*#(?P<id>[^"]+)""#).unwrap();
+    let epub_type = Regex::new(r#"\sepub:type="[^"]*""#).unwrap();
+    let back_link = Regex::new(r#"href="[^"]*#(?P<id>fnref[^"]+)""#).unwrap();
+
+    let mut referenced = vec![];
+    let xhtml = reference.replace_all(xhtml, |caps: &Captures| {
+        let attrs = caps.name("attrs").unwrap();
+        match href.captures(attrs).map(|c| c.name("id").unwrap().to_owned()) {
+            Some(ref id) if notes.contains_key(id) => {
+                referenced.push(id.clone());
+                let href_attr = format!(" href=\"#{}\"", id);
+                let attrs = href.replace_all(&epub_type.replace_all(attrs, ""), &href_attr[..]);
+                format!("<a{} epub:type=\"noteref\">", attrs)
+            }
+            _ => caps.at(0).unwrap().to_owned(),
+        }
+    });
+
+    if referenced.is_empty() {
+        return xhtml;
+    }
+
+    let mut asides = String::new();
+    for id in referenced {
+        asides.push_str(&format!("<aside epub:type=\"footnote\" id=\"{}\">\n{}\n</aside>\n",
+                                 id,
+                                 back_link.replace_all(&notes[&id], "href=\"#$id\"")));
+    }
+    xhtml.replacen("</body>", &format!("{}</body>", asides), 1)
+}
+
+fn nav_list(nav_type: &str, title: &str, links: &[(String, String, Option<&str>)]) -> String {
+    let mut nav = format!("<nav epub:type=\"{0}\" id=\"{0}\" hidden=\"hidden\">\n<h1>{1}</h1>\n<ol>\n",
+                          nav_type,
+                          title);
+    for &(ref href, ref text, link_type) in links {
+        nav.push_str(&format!("<li><a href=\"{}\"{}>{}</a></li>\n",
+                              href,
+                              link_type.map_or(String::new(), |t| format!(" epub:type=\"{}\"", t)),
+                              text));
+    }
+    nav.push_str("</ol>\n</nav>\n");
+    nav
+}
+
+/// XOR the start of a font file with the SHA-1 of the book's unique
+/// identifier (without whitespace)
+fn obfuscate(font: &[u8], identifier: &str) -> Vec<u8> {
+    let identifier: String = identifier.chars().filter(|c| !" \t\r\n".contains(*c)).collect();
+    let key = Sha1::digest(identifier.as_bytes());
+
+    font.iter()
+        .enumerate()
+        .map(|(i, byte)| if i < OBFUSCATED_BYTES { byte ^ key[i % key.len()] } else { *byte })
+        .collect()
+}
+
+fn embed_font(entries: &mut Vec<Entry>, opf_path: &str, font: &EmbeddedFont) -> Result<(), Box<Error>> {
+    let opf_index = try!(entries.iter().position(|e| e.name == opf_path).ok_or("No OPF file"));
+    let mut opf = text(&entries[opf_index]);
+    let identifier = {
+        let unique = Regex::new(r#"unique-identifier="(?P<id>[^"]+)""#).unwrap();
+        let id = try!(unique.captures(&opf).and_then(|c| c.name("id")).ok_or("No unique identifier"));
+        let value = Regex::new(&format!(r#"<dc:identifier[^>]*\sid="{}"[^>]*>(?P<value>[^<]*)<"#, id)).unwrap();
+        try!(value.captures(&opf).and_then(|c| c.name("value")).ok_or("No unique identifier")).to_owned()
+    };
+
+    let stylesheets: Vec<String> = manifest_items(&opf, opf_path).into_iter()
+                                                                 .filter(|item| item.media_type == "text/css")
+                                                                 .map(|item| item.path)
+                                                                 .collect();
+    let mut items = String::new();
+    let mut font_faces: BTreeMap<String, String> = BTreeMap::new();
+    let mut encrypted = String::new();
+
+    for (number, file) in font.files.iter().enumerate() {
+        let mut data = vec![];
+        try!(try!(File::open(&file.path)).read_to_end(&mut data));
+        let name = file.path.rsplit('/').next().unwrap_or(&file.path);
+        let path = format!("{}fonts/{}", parent(opf_path), name);
+
+        if font.obfuscate {
+            data = obfuscate(&data, &identifier);
+            encrypted.push_str(&format!("  <enc:EncryptedData>\n    \
+                                         <enc:EncryptionMethod Algorithm=\"http://www.idpf.org/2008/embedding\"/>\n    \
+                                         <enc:CipherData><enc:CipherReference URI=\"{}\"/></enc:CipherData>\n  \
+                                         </enc:EncryptedData>\n",
+                                        path));
+        }
+        items.push_str(&format!("<item id=\"embedded-font-{}\" href=\"{}\" \
+                                 media-type=\"application/vnd.ms-opentype\" />\n",
+                                number,
+                                relative(opf_path, &path)));
+        for stylesheet in &stylesheets {
+            font_faces.entry(stylesheet.clone()).or_insert(String::new()).push_str(&format!(
+                "@font-face {{ font-family: \"{}\"; font-weight: {}; font-style: {}; src: url({}); }}\n",
+                font.family,
+                if file.bold { "bold" } else { "normal" },
+                if file.italic { "italic" } else { "normal" },
+                relative(stylesheet, &path)));
+        }
+        entries.push(Entry { name: path, data: data, stored: false });
+    }
+
+    opf = opf.replacen("</manifest>", &format!("{}</manifest>", items), 1);
+    entries[opf_index].data = opf.into_bytes();
+
+    for entry in entries.iter_mut() {
+        if let Some(font_face) = font_faces.get(&entry.name) {
+            let css = format!("{}\n{}code, pre {{ font-family: \"{}\", monospace; }}\n",
+                              text(entry),
+                              font_face,
+                              font.family);
+            entry.data = css.into_bytes();
+        }
+    }
+
+    if font.obfuscate {
+        entries.retain(|e| e.name != "META-INF/encryption.xml");
+        entries.push(Entry {
+            name: "META-INF/encryption.xml".to_owned(),
+            data: format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                           <encryption xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\" \
+                           xmlns:enc=\"http://www.w3.org/2001/04/xmlenc#\">\n{}</encryption>\n",
+                          encrypted)
+                      .into_bytes(),
+            stored: false,
+        });
+    }
+
+    Ok(())
+}
+
+/// Add the semantics pandoc leaves out to the EPUB at `path`
+pub fn finish_epub(path: &str, settings: &EpubSettings) -> Result<(), Box<Error>> {
+    let id = Regex::new(r#"\sid="(?P<id>[^"]+)""#).unwrap();
+    let first_heading = Regex::new(r"(?s)<h1[^>]*>(?P<title>.*?)</h1>").unwrap();
+    let tag = Regex::new(r"<[^>]+>").unwrap();
+
+    let mut entries = try!(read_entries(path));
+
+    let opf_path = try!(opf_path(&entries).ok_or("No OPF file in META-INF/container.xml"));
+    let opf = text(try!(entries.iter().find(|e| e.name == opf_path).ok_or("No OPF file")));
+    let items = manifest_items(&opf, &opf_path);
+    let spine = spine(&opf, &items);
+    let nav_path = try!(items.iter()
+                             .find(|item| item.properties.split(' ').any(|p| p == "nav"))
+                             .map(|item| item.path.clone())
+                             .ok_or("No navigation document, is it EPUB 3?"));
+
+    // Footnotes go next to their references
+    let mut notes = BTreeMap::new();
+    for entry in entries.iter_mut().filter(|e| spine.contains(&e.name)) {
+        let (xhtml, found) = take_footnotes(&text(entry));
+        entry.data = add_section_types(&xhtml, settings.glossary, settings.index).into_bytes();
+        notes.extend(found);
+    }
+
+    // Where the IDs are, for the landmarks and page list
+    let mut ids: BTreeMap<String, String> = BTreeMap::new();
+    let mut body_matter: Option<(String, String)> = None;
+    for entry in entries.iter_mut().filter(|e| spine.contains(&e.name)) {
+        let xhtml = place_footnotes(&text(entry), &notes);
+        for caps in id.captures_iter(&xhtml) {
+            ids.entry(caps.name("id").unwrap().to_owned()).or_insert(entry.name.clone());
+        }
+        if body_matter.is_none() && xhtml.contains("epub:type=\"chapter\"") {
+            let title = first_heading.captures(&xhtml)
+                                     .map_or(String::new(), |c| tag.replace_all(c.name("title").unwrap(), ""));
+            body_matter = Some((entry.name.clone(), title));
+        }
+        entry.data = xhtml.into_bytes();
+    }
+
+    let href = |id: &str| ids.get(id).map(|file| format!("{}#{}", relative(&nav_path, file), id));
+
+    let mut landmarks = vec![(format!("{}#toc", relative(&nav_path, &nav_path)),
+                              settings.language.table_of_contents.to_owned(),
+                              Some("toc"))];
+    if let Some((file, title)) = body_matter {
+        landmarks.push((relative(&nav_path, &file), title, Some("bodymatter")));
+    }
+    if let Some(link) = settings.glossary.and_then(|id| href(id)) {
+        landmarks.push((link, settings.language.glossary.to_owned(), Some("glossary")));
+    }
+    if let Some(link) = settings.index.and_then(|id| href(id)) {
+        landmarks.push((link, settings.language.index.to_owned(), Some("index")));
+    }
+    let mut navs = nav_list("landmarks", "Landmarks", &landmarks);
+
+    if let Some(page_map) = settings.page_map {
+        let mut pages = vec![];
+        for &(ref label, ref page) in page_map {
+            if pages.iter().any(|&(_, ref p, _)| p == page) {
+                continue;
+            }
+            if let Some(link) = href(label) {
+                pages.push((link, page.clone(), None));
+            }
+        }
+        navs.push_str(&nav_list("page-list", "Pages", &pages));
+    }
+
+    let landmarks_nav = Regex::new(r#"(?s)<nav[^>]*epub:type="landmarks".*?</nav>\s*"#).unwrap();
+    for entry in entries.iter_mut().filter(|e| e.name == nav_path) {
+        let nav = landmarks_nav.replace_all(&text(entry), "");
+        entry.data = nav.replacen("</body>", &format!("{}</body>", navs), 1).into_bytes();
+    }
+
+    if let Some(font) = settings.font {
+        try!(embed_font(&mut entries, &opf_path, font));
+    }
+
+    write_entries(path, &entries)
+}
+
+#[test]
+fn epub_semantics() {
+    let chapter = r##"<body>
+<section id="sec--glossary" class="level1">
+<h1>Glossary</h1>
+<p>A note<a href="ch009.xhtml#fn1" class="footnoteRef" id="fnref1"><sup>1</sup></a>.</p>
+</section>
+</body>"##;
+    let notes_chapter = r##"<section class="footnotes" epub:type="footnotes">
+<hr />
+<ol>
+<li id="fn1"><p>With a list:</p><ul><li>item</li></ul><a href="ch002.xhtml#fnref1">↩</a></li>
+</ol>
+</section>"##;
+
+    let (without, notes) = take_footnotes(notes_chapter);
+    assert_eq!(without, "");
+    let notes: BTreeMap<String, String> = notes.into_iter().collect();
+    assert_eq!(notes["fn1"], "<p>With a list:</p><ul><li>item</li></ul><a href=\"ch002.xhtml#fnref1\">↩</a>");
+
+    let chapter = add_section_types(chapter, Some("sec--glossary"), None);
+    assert_eq!(place_footnotes(&chapter, &notes), r##"<body>
+<section id="sec--glossary" class="level1" epub:type="glossary">
+<h1>Glossary</h1>
+<p>A note<a href="#fn1" class="footnoteRef" id="fnref1" epub:type="noteref"><sup>1</sup></a>.</p>
+</section>
+<aside epub:type="footnote" id="fn1">
+<p>With a list:</p><ul><li>item</li></ul><a href="#fnref1">↩</a>
+</aside>
+</body>"##);
+
+    assert_eq!(resolve("EPUB/content.opf", "text/ch001.xhtml"), "EPUB/text/ch001.xhtml");
+    assert_eq!(relative("EPUB/styles/stylesheet.css", "EPUB/fonts/mono.ttf"), "../fonts/mono.ttf");
+    assert_eq!(relative("content.opf", "fonts/mono.ttf"), "fonts/mono.ttf");
+
+    let font = vec![7u8; 2000];
+    let obfuscated = obfuscate(&font, "urn:uuid:1234 ");
+    assert_eq!(obfuscate(&obfuscated, "urn:uuid:1234"), font);
+    assert_eq!(obfuscated[OBFUSCATED_BYTES..], font[OBFUSCATED_BYTES..]);
+    // The key is the SHA-1 of the identifier, a9993e36… for `abc`
+    assert_eq!(obfuscate(&[0; 4], "a b c"), vec![0xa9, 0x99, 0x3e, 0x36]);
+}
@@ -0,0 +1,304 @@
+//! Check the structure of the EPUB
+//!
+//! Some readers (like Apple Books) reject EPUBs that epubcheck would complain
+//! about. We can't run epubcheck without Java, so this checks the things that
+//! usually go wrong: the `mimetype` file, the OPF manifest, well-formed XHTML,
+//! internal links and `alt` texts of images.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use regex::Regex;
+
+use convert_book::epub::{Entry, read_entries, opf_path, manifest_items, spine, resolve, text, attribute};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Something wrong with a file in the EPUB
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Path of the file in the container
+    pub path: String,
+    pub message: String,
+}
+
+fn error<S: Into<String>>(path: &str, message: S) -> Problem {
+    Problem { severity: Severity::Error, path: path.to_owned(), message: message.into() }
+}
+
+fn warning<S: Into<String>>(path: &str, message: S) -> Problem {
+    Problem { severity: Severity::Warning, path: path.to_owned(), message: message.into() }
+}
+
+fn line_of(text: &str, offset: usize) -> usize {
+    text[..offset].matches('\n').count() + 1
+}
+
+/// The first thing that makes `xhtml` not well-formed XML
+fn check_well_formed(xhtml: &str) -> Option<String> {
+    let tag = Regex::new(r#"^<(?P<end>/?)(?P<name>[A-Za-z_][\w:.-]*)(\s+[\w:.-]+\s*=\s*("[^"<]*"|'[^'<]*'))*\s*(?P<empty>/?)>"#).unwrap();
+    let entity = Regex::new(r"^&(amp|lt|gt|quot|apos|#[0-9]+|#x[0-9a-fA-F]+);").unwrap();
+    let mut open: Vec<(&str, usize)> = vec![];
+    let mut position = 0;
+
+    while let Some(offset) = xhtml[position..].find(|c| c == '<' || c == '&') {
+        let start = position + offset;
+        let rest = &xhtml[start..];
+
+        if rest.starts_with('&') {
+            if !entity.is_match(rest) {
+                return Some(format!("line {}: `&` that doesn't start an XML entity", line_of(xhtml, start)));
+            }
+            position = start + 1;
+            continue;
+        }
+
+        let special = [("<!--", "-->"), ("<![CDATA[", "]]>"), ("<?", "?>"), ("<!", ">")];
+        if let Some(&(_, end)) = special.iter().find(|&&(begin, _)| rest.starts_with(begin)) {
+            match rest.find(end) {
+                Some(length) => position = start + length + end.len(),
+                None => return Some(format!("line {}: Unclosed `{}`", line_of(xhtml, start), &rest[..2])),
+            }
+            continue;
+        }
+
+        let caps = match tag.captures(rest) {
+            Some(caps) => caps,
+            None => return Some(format!("line {}: Malformed tag or unescaped `<`", line_of(xhtml, start))),
+        };
+        let length = caps.at(0).unwrap().len();
+        if let Some(ampersand) = caps.at(0).unwrap().find('&') {
+            if !entity.is_match(&rest[ampersand..]) {
+                return Some(format!("line {}: `&` that doesn't start an XML entity", line_of(xhtml, start)));
+            }
+        }
+
+        let name = caps.name("name").unwrap();
+        if caps.name("end") == Some("/") {
+            match open.pop() {
+                Some((opened, _)) if opened == name => {}
+                Some((opened, line)) => {
+                    return Some(format!("line {}: `</{}>` closes `<{}>` from line {}",
+                                        line_of(xhtml, start),
+                                        name,
+                                        opened,
+                                        line))
+                }
+                None => return Some(format!("line {}: `</{}>` was never opened", line_of(xhtml, start), name)),
+            }
+        } else if caps.name("empty") != Some("/") {
+            open.push((name, line_of(xhtml, start)));
+        }
+        position = start + length;
+    }
+
+    open.pop().map(|(name, line)| format!("line {}: `<{}>` is never closed", line, name))
+}
+
+/// Decode the entities and percent-encoding of an `href`
+fn decode_href(href: &str) -> String {
+    let href = href.replace("&amp;", "&");
+    let bytes = href.as_bytes();
+    let mut decoded = vec![];
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let escaped = if bytes[index] == b'%' && index + 2 < bytes.len() {
+            match ((bytes[index + 1] as char).to_digit(16), (bytes[index + 2] as char).to_digit(16)) {
+                (Some(high), Some(low)) => Some((high * 16 + low) as u8),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn check_entries(entries: &[Entry]) -> Vec<Problem> {
+    let link = Regex::new(r#"\s(xlink:)?(href|src)="(?P<href>[^"]*)""#).unwrap();
+    let external = Regex::new(r"^[a-zA-Z][a-zA-Z0-9+.-]*:").unwrap();
+    let id = Regex::new(r#"\sid="(?P<id>[^"]*)""#).unwrap();
+    let image = Regex::new(r"<img\b[^>]*>").unwrap();
+    let mut problems = vec![];
+
+    match entries.first() {
+        Some(first) if first.name == "mimetype" => {
+            if !first.stored {
+                problems.push(error("mimetype", "Must not be compressed"));
+            }
+            if first.data != b"application/epub+zip" {
+                problems.push(error("mimetype", "Must contain `application/epub+zip`"));
+            }
+        }
+        _ => problems.push(error("mimetype", "Must be the first file")),
+    }
+
+    let opf_path = match opf_path(entries) {
+        Some(path) => path,
+        None => {
+            problems.push(error("META-INF/container.xml", "Doesn't name an OPF file"));
+            return problems;
+        }
+    };
+    let opf = match entries.iter().find(|e| e.name == opf_path) {
+        Some(opf) => text(opf),
+        None => {
+            problems.push(error(&opf_path, "Is missing"));
+            return problems;
+        }
+    };
+
+    let files: BTreeSet<&str> = entries.iter().map(|e| &e.name[..]).collect();
+    let items = manifest_items(&opf, &opf_path);
+
+    for item in &items {
+        if !files.contains(&item.path[..]) {
+            problems.push(error(&opf_path, format!("Manifest lists missing file `{}`", item.path)));
+        }
+    }
+    for entry in entries {
+        let listed = items.iter().any(|item| item.path == entry.name);
+        if !listed && entry.name != "mimetype" && entry.name != opf_path && !entry.name.starts_with("META-INF/") {
+            problems.push(warning(&entry.name, "Is not listed in the OPF manifest"));
+        }
+    }
+    if spine(&opf, &items).is_empty() {
+        problems.push(error(&opf_path, "The spine is empty"));
+    }
+    if !items.iter().any(|item| item.properties.split(' ').any(|p| p == "nav")) {
+        problems.push(warning(&opf_path, "Has no navigation document"));
+    }
+
+    let documents: Vec<&Entry> = entries.iter()
+                                        .filter(|e| e.name.ends_with(".xhtml") || e.name.ends_with(".html"))
+                                        .collect();
+    let ids: BTreeMap<&str, BTreeSet<String>> = documents.iter()
+        .map(|document| {
+            let xhtml = text(document);
+            (&document.name[..], id.captures_iter(&xhtml).map(|c| c.name("id").unwrap().to_owned()).collect())
+        })
+        .collect();
+
+    for document in documents {
+        let xhtml = text(document);
+
+        if let Some(message) = check_well_formed(&xhtml) {
+            problems.push(error(&document.name, message));
+        }
+
+        for caps in link.captures_iter(&xhtml) {
+            let href = decode_href(caps.name("href").unwrap());
+            if external.is_match(&href) {
+                continue;
+            }
+            let (file, fragment) = match href.find('#') {
+                Some(index) => (&href[..index], Some(&href[index + 1..])),
+                None => (&href[..], None),
+            };
+            let target = if file.is_empty() { document.name.clone() } else { resolve(&document.name, file) };
+
+            if !files.contains(&target[..]) {
+                problems.push(error(&document.name, format!("Link to missing file `{}`", href)));
+            } else if let (Some(fragment), Some(target_ids)) = (fragment, ids.get(&target[..])) {
+                if !fragment.is_empty() && !target_ids.contains(fragment) {
+                    problems.push(error(&document.name,
+                                        format!("Link to missing anchor `{}`", href)));
+                }
+            }
+        }
+
+        for (start, end) in image.find_iter(&xhtml) {
+            let tag = &xhtml[start..end];
+            if attribute(tag, "alt").is_none() {
+                problems.push(warning(&document.name,
+                                      format!("line {}: Image `{}` has no `alt` text",
+                                              line_of(&xhtml, start),
+                                              attribute(tag, "src").unwrap_or(String::new()))));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Check the EPUB at `path` and list its problems
+///
+/// Returns whether it has no errors (warnings are fine).
+pub fn check_epub(path: &str) -> Result<bool, Box<Error>> {
+    let problems = check_entries(&try!(read_entries(path)));
+    let errors = problems.iter().filter(|p| p.severity == Severity::Error).count();
+
+    for problem in &problems {
+        let marker = match problem.severity {
+            Severity::Error => "✗",
+            Severity::Warning => "!",
+        };
+        println!("[{}] {}: {}", marker, problem.path, problem.message);
+    }
+
+    println!("[{}] EPUB check: {} errors, {} warnings",
+             if errors == 0 { "✓" } else { "✗" },
+             errors,
+             problems.len() - errors);
+
+    Ok(errors == 0)
+}
+
+#[test]
+fn epub_problems() {
+    let entry = |name: &str, data: &str| {
+        Entry { name: name.to_owned(), data: data.as_bytes().to_vec(), stored: false }
+    };
+    let entries = vec![
+        entry("mimetype", "application/epub+zip"),
+        entry("META-INF/container.xml", r#"<rootfile full-path="EPUB/content.opf" />"#),
+        entry("EPUB/content.opf", r#"<manifest>
+<item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav" />
+<item id="ch001" href="text/ch001.xhtml" media-type="application/xhtml+xml" />
+<item id="ch002" href="text/ch002.xhtml" media-type="application/xhtml+xml" />
+</manifest><spine><itemref idref="ch001" /></spine>"#),
+        entry("EPUB/nav.xhtml", r#"<html><body><a href="text/ch001.xhtml#intro">Intro</a></body></html>"#),
+        entry("EPUB/text/ch001.xhtml", r##"<html><body>
+<p id="intro">A &amp; B<br/><a href="#outro">x</a> <a href="http://rust-lang.org/">y</a></p>
+<img src="../images/logo.png" />
+<p>Not closed
+</body></html>"##),
+        entry("EPUB/images/unlisted.png", ""),
+    ];
+
+    let problems: Vec<(Severity, String, String)> = check_entries(&entries)
+        .into_iter()
+        .map(|p| (p.severity, p.path, p.message))
+        .collect();
+    let expected = vec![
+        (Severity::Error, "mimetype", "Must not be compressed"),
+        (Severity::Error, "EPUB/content.opf", "Manifest lists missing file `EPUB/text/ch002.xhtml`"),
+        (Severity::Warning, "EPUB/images/unlisted.png", "Is not listed in the OPF manifest"),
+        (Severity::Error, "EPUB/text/ch001.xhtml", "line 5: `</body>` closes `<p>` from line 4"),
+        (Severity::Error, "EPUB/text/ch001.xhtml", "Link to missing anchor `#outro`"),
+        (Severity::Error, "EPUB/text/ch001.xhtml", "Link to missing file `../images/logo.png`"),
+        (Severity::Warning, "EPUB/text/ch001.xhtml", "line 3: Image `../images/logo.png` has no `alt` text"),
+    ];
+    assert_eq!(problems,
+               expected.into_iter()
+                       .map(|(severity, path, message)| (severity, path.to_owned(), message.to_owned()))
+                       .collect::<Vec<_>>());
+
+    assert_eq!(decode_href("ch%20001.xhtml#a&amp;b"), "ch 001.xhtml#a&b");
+}
@@ -0,0 +1,84 @@
+//! Files that come before the chapters listed in `SUMMARY.md`
+//!
+//! Configured in the meta data file like this:
+//!
+//! ```yaml
+//! front-matter:
+//!   - file: foreword.md
+//!     title: "Foreword"
+//!   - file: README.md
+//!     numbered: false
+//! ```
+//!
+//! Without this configuration, the book's `README.md` (if it has one) becomes
+//! the introduction.
+
+use std::error::Error;
+use rustc_serialize::json::Json;
+
+use convert_book::localization::Language;
+
+pub const README: &'static str = "README.md";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct FrontMatter {
+    pub file: String,
+    /// Defaults to the file's `% Title`, or the localized "Introduction" for
+    /// `README.md`
+    pub title: Option<String>,
+    pub numbered: bool,
+    /// Only the default `README.md` may be missing
+    pub optional: bool,
+}
+
+impl FrontMatter {
+    /// The title of this part of the book, `file_title` being the `% Title`
+    /// line of its file
+    pub fn title(&self, file_title: Option<&str>, language: &Language) -> String {
+        match (self.title.as_ref(), file_title) {
+            (Some(title), _) => title.clone(),
+            (None, _) if self.file == README => language.introduction.to_owned(),
+            (None, Some(title)) => title.to_owned(),
+            (None, None) => self.file.clone(),
+        }
+    }
+}
+
+/// Read the front matter configuration from the book's meta data
+pub fn get_front_matter(meta: &Json) -> Result<Vec<FrontMatter>, Box<Error>> {
+    let entries = match meta.find("front-matter") {
+        Some(&Json::Array(ref entries)) => entries,
+        Some(_) => return Err(From::from("`front-matter` needs to be a list")),
+        None => {
+            return Ok(vec![FrontMatter {
+                               file: README.to_owned(),
+                               title: None,
+                               numbered: false,
+                               optional: true,
+                           }])
+        }
+    };
+
+    let mut front_matter = vec![];
+
+    for entry in entries {
+        let file = match *entry {
+            Json::String(ref file) => file.clone(),
+            _ => {
+                try!(entry.find("file")
+                          .and_then(Json::as_string)
+                          .ok_or("Front matter entries need a `file`"))
+                    .to_owned()
+            }
+        };
+
+        front_matter.push(FrontMatter {
+            file: file,
+            title: entry.find("title").and_then(Json::as_string).map(str::to_owned),
+            numbered: entry.find("numbered").and_then(Json::as_boolean).unwrap_or(false),
+            optional: false,
+        });
+    }
+
+    Ok(front_matter)
+}
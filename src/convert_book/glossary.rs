@@ -0,0 +1,121 @@
+//! Where the glossary terms come from
+//!
+//! By default, the headings of the book's `glossary.md` chapter are the terms.
+//! A YAML file mapping terms to definitions can be used instead, which adds a
+//! glossary to the end of the book:
+//!
+//! ```yaml
+//! glossary: glossary.yml # or `false` to not link any terms
+//! ```
+
+use std::error::Error;
+use std::path::Path;
+use regex::Regex;
+use rustc_serialize::json::Json;
+
+use helpers;
+use helpers::adjust_header_ids::{auto_identifier, section_id};
+use helpers::glossary::{GlossaryEntry, term_names};
+use convert_book::markdown::file_id;
+
+const CODE_BLOCK_TOGGLE: &'static str = "```";
+
+pub const GLOSSARY_CHAPTER: &'static str = "glossary.md";
+
+/// The glossary's entries and the ID of the chapter containing them (`None`
+/// for YAML files)
+pub struct Glossary {
+    pub entries: Vec<GlossaryEntry>,
+    pub chapter: Option<String>,
+}
+
+fn from_chapter(src_path: &Path, file: &str) -> Result<Glossary, Box<Error>> {
+    let content = try!(helpers::file::get_file_content(&src_path.join(file)));
+    let id = file_id(file);
+
+    Ok(Glossary { entries: chapter_entries(&content, &id), chapter: Some(format!("sec--{}", id)) })
+}
+
+/// The headings of the glossary chapter, except for comments in code blocks
+fn chapter_entries(content: &str, id: &str) -> Vec<GlossaryEntry> {
+    // Level 1 is the chapter's title, not a term
+    let heading = Regex::new(r"^##+\s+(?P<title>.+?)\s*$").unwrap();
+    let mut entries = vec![];
+    let mut in_code_block = false;
+
+    for line in content.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+        if in_code_block {
+            continue;
+        }
+        if let Some(caps) = heading.captures(line) {
+            let title = caps.name("title").unwrap();
+            entries.push(GlossaryEntry {
+                names: term_names(title),
+                id: section_id(id, &auto_identifier(title)),
+                definition: None,
+            });
+        }
+    }
+
+    entries
+}
+
+fn from_yaml(src_path: &Path, file: &str) -> Result<Glossary, Box<Error>> {
+    let content = try!(helpers::file::get_file_content(&src_path.join(file)));
+    let terms = match try!(helpers::yaml::parse_yaml(&content)) {
+        Json::Object(terms) => terms,
+        _ => return Err(From::from(format!("`{}` needs to map terms to definitions", file))),
+    };
+
+    let mut entries = vec![];
+    for (term, definition) in terms {
+        let definition = try!(definition.as_string()
+                                        .ok_or_else(|| format!("Definition of `{}` needs to be text", term)));
+        entries.push(GlossaryEntry {
+            names: term_names(&term),
+            id: format!("glossary--{}", auto_identifier(&term)),
+            definition: Some(definition.to_owned()),
+        });
+    }
+
+    Ok(Glossary { entries: entries, chapter: None })
+}
+
+/// Read the glossary configured in the book's meta data
+pub fn get_glossary(meta: &Json, src_path: &Path) -> Result<Option<Glossary>, Box<Error>> {
+    let file = match meta.find("glossary") {
+        Some(&Json::Boolean(false)) => return Ok(None),
+        Some(&Json::String(ref file)) => file.clone(),
+        Some(_) => return Err(From::from("`glossary` needs to be a file name or `false`")),
+        None if src_path.join(GLOSSARY_CHAPTER).exists() => GLOSSARY_CHAPTER.to_owned(),
+        None => return Ok(None),
+    };
+
+    if file.ends_with(".yml") || file.ends_with(".yaml") {
+        from_yaml(src_path, &file).map(Some)
+    } else {
+        from_chapter(src_path, &file).map(Some)
+    }
+}
+
+#[test]
+fn glossary_chapter_entries() {
+    let chapter = "# Glossary
+
+### Expression
+
+```rust
+# fn main() {}
+```
+
+### DST (Dynamically Sized Type)
+";
+
+    let entries = chapter_entries(chapter, "glossary");
+    assert_eq!(entries.iter().map(|e| &e.id[..]).collect::<Vec<_>>(),
+               vec!["sec--glossary--expression",
+                    "sec--glossary--dst-dynamically-sized-type"]);
+}
@@ -1,76 +1,434 @@
+//! The download page listing the releases in `dist/`
+//!
+//! Books are grouped by their prefix (and language), releases by date. The
+//! title and homepage of a book come from the meta data at the top of its
+//! Markdown release.
+
 use std::error::Error;
 use std::path::Path;
-use std::fs;
-use std::collections::BTreeMap;
-use std::ascii::AsciiExt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::collections::{BTreeMap, BTreeSet};
+use rustc_serialize::json::Json;
 
 use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use helpers;
+use convert_book::localization::{self, DEFAULT_LANGUAGE};
+use convert_book::page_map::pdf_texts;
+use convert_book::pdf;
+use convert_book::prune::{ARCHIVE_DIR, Retention, pruned_files};
 
 const FILENAME_PATTRN: &'static str =
-    r"^(?P<prefix>\w+)-(?P<date>\d{4}-\d{2}-\d{2})\.(?P<ext>.+)$";
+    r"^(?P<prefix>\w+)(-(?P<lang>[a-zA-Z]{2,3}(-[a-zA-Z]{2,4})?))?-(?P<date>\d{4}-\d{2}-\d{2})\.(?P<ext>.+)$";
+
+/// Used instead of the date in the names of the copies of a book's newest
+/// files, e.g. `trpl-latest.epub`
+pub const LATEST: &'static str = "latest";
+
+/// The file name that always has the newest release of a book's format
+pub fn latest_alias(book: &str, format: &str) -> String {
+    format!("{}-{}.{}", book, LATEST, format)
+}
+
+/// Copy the files of a book's release from `date` to their `latest` aliases
+pub fn update_latest_aliases(path: &str, book: &str, date: &str) -> Result<(), Box<Error>> {
+    let release = format!("{}-{}.", book, date);
+
+    for entry in try!(fs::read_dir(&Path::new(path))) {
+        let entry = try!(entry);
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(&release) {
+            try!(fs::copy(entry.path(), Path::new(path).join(latest_alias(book, &name[release.len()..]))));
+        }
+    }
+
+    Ok(())
+}
 
-type FileListing = Vec<(String, String)>;
+/// A file of a release
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Download {
+    /// Prefix and language tag (if not the default), e.g. `trpl` or `trpl-ja`
+    pub book: String,
+    pub language: String,
+    pub date: String,
+    /// Everything after the date, e.g. `a4.pdf`
+    pub format: String,
+    pub file_name: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Only known for PDFs
+    pub pages: Option<usize>,
+}
+
+/// What a book's meta data says about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BookInfo {
+    pub title: String,
+    pub author: Option<String>,
+    pub homepage: Option<String>,
+}
 
-fn list_file_groups(path: &str) -> Result<FileListing, Box<Error>> {
+fn read_bytes(path: &Path) -> Result<Vec<u8>, Box<Error>> {
+    let mut content = vec![];
+    try!(try!(File::open(path)).read_to_end(&mut content));
+    Ok(content)
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Number of pages of a PDF, also if its page tree is in a compressed object stream
+fn pdf_page_count(content: &[u8]) -> Option<usize> {
+    let pages = Regex::new(r"<<[^<>]*/Type\s*/Pages\b[^<>]*>>").unwrap();
+    let count = Regex::new(r"/Count\s+(\d+)").unwrap();
+    let page = Regex::new(r"/Type\s*/Page\b").unwrap();
+    // The page tree is in an object stream in PDF 1.5 and later
+    let texts = pdf_texts(content);
+
+    // The root of the page tree counts all pages
+    texts.iter()
+         .flat_map(|text| pages.find_iter(text).map(move |(start, end)| &text[start..end]))
+         .filter_map(|dictionary| count.captures(dictionary))
+         .filter_map(|caps| caps.at(1).and_then(|n| n.parse().ok()))
+         .max()
+         .or_else(|| {
+             match texts.iter().map(|text| page.find_iter(text).count()).sum() {
+                 0 => None,
+                 n => Some(n),
+             }
+         })
+}
+
+/// All dated files in `path`, newest first
+pub fn list_downloads(path: &str) -> Result<Vec<Download>, Box<Error>> {
     let filename_pattern = Regex::new(FILENAME_PATTRN).unwrap();
+    let mut downloads = vec![];
+
+    for entry in try!(fs::read_dir(&Path::new(path))) {
+        let path = try!(entry).path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_owned(),
+            None => continue,
+        };
 
-    let files = try!(fs::read_dir(&Path::new(path)))
-    .filter(Result::is_ok)
-    .map(|x| x.unwrap().path())
-    .filter_map(|x| {
-        x.file_name()
-         .and_then(|a| { a.to_str() })
-         .and_then(|b| -> Option<String> { Some(b.into()) })
-    })
-    .flat_map(|name| -> Option<(String, String)> {
-        // Extract the date from names like 'trpl-2015-05-13.a4.pdf'.
+        // Extract the date from names like 'trpl-2015-05-13.a4.pdf' or
+        // 'trpl-ja-2015-05-13.a4.pdf'.
         // This also excludes the `index.html` file as it contains no date.
-        if let Some(caps) = filename_pattern.captures(&name) {
-            if let Some(version) = caps.name("date") {
-                return Some((version.to_owned(), name.to_owned()));
+        let caps = match filename_pattern.captures(&name) {
+            Some(caps) => caps,
+            None => continue,
+        };
+
+        let content = try!(read_bytes(&path));
+        let format = caps.name("ext").unwrap().to_owned();
+        downloads.push(Download {
+            book: match caps.name("lang") {
+                Some(lang) => format!("{}-{}", caps.name("prefix").unwrap(), lang),
+                None => caps.name("prefix").unwrap().to_owned(),
+            },
+            language: caps.name("lang").unwrap_or(DEFAULT_LANGUAGE).to_owned(),
+            date: caps.name("date").unwrap().to_owned(),
+            pages: if format.ends_with("pdf") { pdf_page_count(&content) } else { None },
+            format: format,
+            file_name: name.clone(),
+            size: content.len() as u64,
+            sha256: sha256_hex(&content),
+        });
+    }
+
+    downloads.sort_by(|a, b| (&a.book, &b.date, &a.format).cmp(&(&b.book, &a.date, &b.format)));
+    Ok(downloads)
+}
+
+/// The YAML blocks at the start of a Markdown release, merged
+fn leading_meta(content: &str) -> BTreeMap<String, Json> {
+    let mut meta = BTreeMap::new();
+    let mut rest = content.trim_left();
+
+    while rest.starts_with("---") {
+        let block = match rest.find("\n...") {
+            Some(end) => &rest[..end + 4],
+            None => break,
+        };
+        if let Ok(Json::Object(values)) = helpers::yaml::parse_yaml(block) {
+            meta.extend(values);
+        }
+        rest = rest[block.len()..].trim_left();
+    }
+
+    meta
+}
+
+/// The releases in a directory
+pub struct Releases {
+    /// Sorted by book, newest first
+    pub downloads: Vec<Download>,
+    /// From the meta data of each book's newest Markdown release
+    pub books: BTreeMap<String, BookInfo>,
+    /// Git revision of the sources of each release, by book and date
+    pub revisions: BTreeMap<(String, String), String>,
+    /// Whether there are archived releases to link to
+    pub archive: bool,
+    /// The `latest` aliases of the newest files
+    pub aliases: BTreeSet<String>,
+}
+
+impl Releases {
+    pub fn scan(path: &str) -> Result<Releases, Box<Error>> {
+        let downloads = try!(list_downloads(path));
+        let mut books = BTreeMap::new();
+        let mut revisions = BTreeMap::new();
+
+        for download in downloads.iter().filter(|d| d.format == "md") {
+            let content = try!(helpers::file::get_file_content(Path::new(path).join(&download.file_name)));
+            let meta = leading_meta(&content);
+            let find = |key: &str| meta.get(key).and_then(Json::as_string).map(str::to_owned);
+
+            if let Some(revision) = find("source-revision") {
+                revisions.insert((download.book.clone(), download.date.clone()), revision);
+            }
+            if !books.contains_key(&download.book) {
+                books.insert(download.book.clone(),
+                             BookInfo {
+                                 title: find("title").unwrap_or(download.book.clone()),
+                                 author: find("author"),
+                                 homepage: find("homepage"),
+                             });
             }
         }
-        return None;
-    })
-    .collect();
 
-    Ok(files)
+        // Books without a Markdown release are named by their prefix
+        for download in &downloads {
+            if !books.contains_key(&download.book) {
+                books.insert(download.book.clone(),
+                             BookInfo { title: download.book.clone(), author: None, homepage: None });
+            }
+        }
+
+        // Only link to aliases that are copies of the newest release
+        let mut aliases = BTreeSet::new();
+        for download in &downloads {
+            let newest = downloads.iter().find(|d| d.book == download.book).map(|d| &d.date);
+            let alias = latest_alias(&download.book, &download.format);
+            if newest != Some(&download.date) {
+                continue;
+            }
+            // An alias with the same size can still be the copy of an older
+            // release that was rebuilt
+            let is_copy = read_bytes(&Path::new(path).join(&alias))
+                              .map(|content| sha256_hex(&content) == download.sha256)
+                              .unwrap_or(false);
+            if is_copy {
+                aliases.insert(alias);
+            }
+        }
+
+        Ok(Releases {
+            downloads: downloads,
+            books: books,
+            revisions: revisions,
+            archive: Path::new(path).join(ARCHIVE_DIR).is_dir(),
+            aliases: aliases,
+        })
+    }
+
+    /// Leave out the releases `retention` doesn't keep
+    pub fn retain(&mut self, retention: &Retention) {
+        let pruned: Vec<String> = pruned_files(self, retention).iter()
+                                                              .map(|file| file.file_name.clone())
+                                                              .collect();
+        self.downloads.retain(|download| !pruned.contains(&download.file_name));
+    }
+
+    /// Releases (date and files) of each book, newest first
+    pub fn by_book(&self) -> BTreeMap<&str, Vec<(&str, Vec<&Download>)>> {
+        let mut books: BTreeMap<&str, Vec<(&str, Vec<&Download>)>> = BTreeMap::new();
+        for download in &self.downloads {
+            let releases = books.entry(&download.book).or_insert(vec![]);
+            if releases.last().map_or(false, |&(date, _)| date == download.date) {
+                releases.last_mut().unwrap().1.push(download);
+            } else {
+                releases.push((&download.date, vec![download]));
+            }
+        }
+        books
+    }
+
+    /// The `latest` alias of a file, if it has one
+    pub fn alias(&self, file: &Download) -> Option<String> {
+        let alias = latest_alias(&file.book, &file.format);
+        if self.aliases.contains(&alias) { Some(alias) } else { None }
+    }
+
+    pub fn revision(&self, book: &str, date: &str) -> Option<&str> {
+        self.revisions.get(&(book.to_owned(), date.to_owned())).map(|r| &r[..])
+    }
+
+    /// The titles of all books, e.g. "'A' and 'B'"
+    pub fn title(&self) -> String {
+        let titles: Vec<String> = self.books.values().map(|info| format!("'{}'", info.title)).collect();
+        match titles.split_last() {
+            Some((last, others)) if !others.is_empty() => format!("{} and {}", others.join(", "), last),
+            Some((last, _)) => last.clone(),
+            None => "Rust".to_owned(),
+        }
+    }
 }
 
-pub fn render_index(path: &str) -> Result<String, Box<Error>> {
-    let filename_pattern = Regex::new(FILENAME_PATTRN).unwrap();
+/// A human readable name for a format, e.g. "PDF (A4)" for `a4.pdf`
+pub fn format_label(format: &str) -> String {
+    match format {
+        "md" => "Markdown".to_owned(),
+        "tex" => "LaTeX".to_owned(),
+        _ => {
+            let mut parts: Vec<&str> = format.split('.').collect();
+            let ext = parts.pop().unwrap_or("").to_uppercase();
+            let variant: Vec<String> = parts.iter()
+                                            .map(|part| {
+                                                if let Some(profile) = pdf::find_profile(part) {
+                                                    return profile.label.to_owned();
+                                                }
+                                                let mut chars = part.chars();
+                                                chars.next().map_or(String::new(), |first| {
+                                                    first.to_uppercase().chain(chars).collect()
+                                                })
+                                            })
+                                            .collect();
+            if variant.is_empty() {
+                ext
+            } else {
+                format!("{} ({})", ext, variant.join(", "))
+            }
+        }
+    }
+}
 
-    let files = try!(list_file_groups(path));
-    let mut versions: BTreeMap<String, Vec<String>> = BTreeMap::new();
+fn format_size(size: u64) -> String {
+    match size {
+        s if s >= 1024 * 1024 => format!("{:.1} MiB", s as f64 / (1024.0 * 1024.0)),
+        s if s >= 1024 => format!("{:.0} KiB", s as f64 / 1024.0),
+        s => format!("{} bytes", s),
+    }
+}
+
+/// Escape text for HTML and XML
+pub fn escape_html(text: &str) -> String {
+    text.replace("&", "&amp;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;")
+        .replace("'", "&#39;")
+        .replace("\"", "&quot;")
+}
+
+/// The files of a release, linking to the `latest` aliases of `releases` if
+/// given
+fn render_files(files: &[&Download], releases: Option<&Releases>) -> String {
+    let mut listing = String::from("<ul>\n");
 
-    for &(ref version, ref filename) in &files {
-        let file = versions.entry(version.clone()).or_insert(vec![]);
-        file.push(filename.clone());
+    for file in files {
+        let mut details = vec![format_size(file.size)];
+        if let Some(pages) = file.pages {
+            details.push(format!("{} pages", pages));
+        }
+        listing.push_str(&format!(
+            "<li><a href='{href}'>{label}</a> <span class='details'>{details}</span><br>\
+             <code class='checksum' title='SHA-256'>{sha256}</code></li>\n",
+            href = releases.and_then(|r| r.alias(file)).unwrap_or(file.file_name.clone()),
+            label = format_label(&file.format),
+            details = details.join(", "),
+            sha256 = file.sha256));
     }
 
+    listing.push_str("</ul>\n");
+    listing
+}
+
+/// The language attribute and heading of a book
+fn book_heading(book: &str, info: &BookInfo, language: &str) -> (String, String) {
+    let title = escape_html(&info.title);
+    let heading = if language == DEFAULT_LANGUAGE {
+        title
+    } else {
+        let name = localization::find_language(language).map_or(language, |l| l.name);
+        format!("{} ({})", title, name)
+    };
+    (format!("lang='{}' id='{}'", language, book), heading)
+}
+
+/// Render the download page with the `template`, which can contain
+/// `{title}`, `{css}`, `{latest}` and `{file_listing}`
+pub fn render_index(releases: &Releases, template: &str) -> String {
+    let mut latest = String::new();
     let mut file_listing = String::new();
 
-    for (version, files) in versions.iter().rev() {
-        file_listing.push_str("<li>\n<h2>");
-        file_listing.push_str(&version);
-        file_listing.push_str("</h2>\n<ul>");
-
-        for file in files {
-            file_listing.push_str(&format!(
-                "<li><a href='{file_name}'>{file_title}</a></li>\n",
-                file_name = file,
-                file_title = filename_pattern
-                    .replace_all(file, "$prefix $ext")
-                    .to_ascii_uppercase()
-            ));
-        }
+    for (book, releases_of_book) in releases.by_book() {
+        let info = &releases.books[book];
+        let (attributes, heading) = book_heading(book, info, &releases_of_book[0].1[0].language);
 
-        file_listing.push_str("</ul>\n</li>");
+        let (date, ref files) = releases_of_book[0];
+        latest.push_str(&format!("<li {}>\n<h3>{} <small>{}</small></h3>\n", attributes, heading, date));
+        latest.push_str(&render_files(files, Some(releases)));
+        latest.push_str("</li>\n");
+
+        file_listing.push_str(&format!("<li {}>\n<h2>{}</h2>\n", attributes, heading));
+        if let Some(ref homepage) = info.homepage {
+            file_listing.push_str(&format!("<p><a href='{}'>Read '{}' online</a></p>\n",
+                                           escape_html(homepage),
+                                           escape_html(&info.title)));
+        }
+        file_listing.push_str("<ul>\n");
+        for &(date, ref files) in &releases_of_book {
+            file_listing.push_str(&format!("<li>\n<h3>{}</h3>\n", date));
+            file_listing.push_str(&render_files(files, None));
+            file_listing.push_str("</li>\n");
+        }
+        file_listing.push_str("</ul>\n</li>\n");
     }
+    if releases.archive {
+        file_listing.push_str(&format!("<li class='archive'><a href='{}/'>Older releases</a></li>\n", ARCHIVE_DIR));
+    }
+
+    template.replace("{title}", &escape_html(&releases.title()))
+            .replace("{css}", include_str!("../../lib/index.css"))
+            .replace("{latest}", &latest)
+            .replace("{file_listing}", &file_listing)
+}
+
+#[test]
+fn download_details() {
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+
+    assert_eq!(format_label("a4.pdf"), "PDF (A4)");
+    assert_eq!(format_label("letter.pdf"), "PDF (Letter)");
+    assert_eq!(format_label("ereader.pdf"), "PDF (E-reader)");
+    assert_eq!(format_label("epub"), "EPUB");
+    assert_eq!(format_label("md"), "Markdown");
+    assert_eq!(format_size(1536), "2 KiB");
+    assert_eq!(format_size(5 * 1024 * 1024 + 1), "5.0 MiB");
 
-    let output = format!(include_str!("../../lib/index_template.html"),
-                         css = include_str!("../../lib/index.css"),
-                         file_listing = file_listing);
+    let pdf = b"%PDF-1.5\n1 0 obj\n<< /Type /Page /Parent 3 0 R >>\nendobj\n\
+                2 0 obj\n<< /Type /Page /Parent 3 0 R >>\nendobj\n\
+                3 0 obj\n<< /Kids [1 0 R 2 0 R] /Type /Pages /Count 2 >>\nendobj\n";
+    assert_eq!(pdf_page_count(pdf), Some(2));
+    assert_eq!(pdf_page_count(b"%PDF-1.5\n"), None);
 
-    Ok(output)
+    // XeLaTeX puts the page tree into a compressed object stream
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(b"<</Type/Page/Parent 3 0 R>> <</Type/Pages/Count 312/Kids[1 0 R]>>").unwrap();
+    let mut pdf = b"%PDF-1.5\n5 0 obj\n<< /Type /ObjStm /Filter /FlateDecode >>\nstream\n".to_vec();
+    pdf.extend(encoder.finish().unwrap());
+    pdf.extend(b"\nendstream\nendobj\n".iter());
+    assert_eq!(pdf_page_count(&pdf), Some(312));
+
+    // Aliases are no releases
+    let filename_pattern = Regex::new(FILENAME_PATTRN).unwrap();
+    assert!(filename_pattern.is_match("trpl-ja-2016-10-01.a4.pdf"));
+    assert!(!filename_pattern.is_match(&latest_alias("trpl", "a4.pdf")));
+    assert!(!filename_pattern.is_match(&latest_alias("trpl-ja", "epub")));
 }
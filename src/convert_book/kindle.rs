@@ -0,0 +1,151 @@
+//! Kindle versions of the EPUB
+//!
+//! Pandoc can't write Kindle formats, so a local converter turns the EPUB into
+//! MOBI or AZW3: Calibre's `ebook-convert` (both formats) or `kindlegen`
+//! (MOBI only). It gets a copy of the finished EPUB with Kindle styles.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+
+use helpers;
+use convert_book::{epub, options};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KindleFormat {
+    Mobi,
+    Azw3,
+}
+
+impl KindleFormat {
+    pub fn from_str(name: &str) -> Option<KindleFormat> {
+        match name {
+            "mobi" => Some(KindleFormat::Mobi),
+            "azw3" => Some(KindleFormat::Azw3),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            KindleFormat::Mobi => "mobi",
+            KindleFormat::Azw3 => "azw3",
+        }
+    }
+}
+
+/// Which Kindle formats to write and the converter to use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kindle {
+    pub converter: String,
+    pub formats: Vec<KindleFormat>,
+}
+
+impl Kindle {
+    /// Parse a comma separated list of formats, e.g. `mobi,azw3`
+    pub fn new(converter: &str, formats: &str) -> Result<Kindle, Box<Error>> {
+        let formats = try!(formats.split(',')
+                                  .map(|f| KindleFormat::from_str(f.trim()).ok_or(f.trim()))
+                                  .collect::<Result<Vec<_>, _>>()
+                                  .map_err(|f| format!("Unknown Kindle format `{}`, use `mobi` or `azw3`", f)));
+        let kindle = Kindle { converter: converter.to_owned(), formats: formats };
+
+        if kindle.is_kindlegen() && kindle.formats.contains(&KindleFormat::Azw3) {
+            return Err(From::from("kindlegen can only write MOBI files"));
+        }
+        Ok(kindle)
+    }
+
+    fn is_kindlegen(&self) -> bool {
+        Path::new(&self.converter).file_stem().map_or(false, |name| name == "kindlegen")
+    }
+
+    fn convert(&self, epub: &Path, output: &Path) -> Result<(), Box<Error>> {
+        let succeeded = if self.is_kindlegen() {
+            // kindlegen writes the file next to the EPUB and takes no path
+            let name = try!(output.file_name().ok_or("No output file name"));
+            let status = try!(Command::new(&self.converter).arg(epub).arg("-o").arg(name).status());
+            // Exit code 1 means there were only warnings
+            let succeeded = status.success() || status.code() == Some(1);
+            if succeeded {
+                try!(fs::rename(epub.with_file_name(name), output));
+            }
+            succeeded
+        } else {
+            try!(Command::new(&self.converter).arg(epub).arg(output).status()).success()
+        };
+
+        if !succeeded {
+            return Err(From::from(format!("`{}` could not convert the EPUB", self.converter)));
+        }
+        Ok(())
+    }
+}
+
+/// Check that `path` looks like a MOBI/AZW3 file: a Palm database of type
+/// `BOOKMOBI`
+fn validate(path: &Path) -> Result<(), Box<Error>> {
+    let mut header = [0u8; 78];
+    let read = try!(try!(File::open(path)).read(&mut header));
+
+    if read < header.len() || &header[60..68] != b"BOOKMOBI" {
+        return Err(From::from(format!("`{}` is no Kindle book", path.display())));
+    }
+    Ok(())
+}
+
+/// Write the Kindle versions of the finished EPUB at `epub`, converting a copy
+/// of it that has `lib/kindle.css` as its stylesheet
+pub fn save_as_kindle(epub: &str, prefix: &str, kindle: &Kindle) -> Result<(), Box<Error>> {
+    let mut entries = try!(epub::read_entries(epub));
+    let opf_path = try!(epub::opf_path(&entries).ok_or("No OPF file"));
+    let opf = {
+        let opf = try!(entries.iter().find(|e| e.name == opf_path).ok_or("No OPF file"));
+        epub::text(opf)
+    };
+    let stylesheets: Vec<String> = epub::manifest_items(&opf, &opf_path)
+                                       .into_iter()
+                                       .filter(|item| item.media_type == "text/css")
+                                       .map(|item| item.path)
+                                       .collect();
+    if stylesheets.is_empty() {
+        return Err(From::from("The EPUB has no stylesheet"));
+    }
+
+    // The Kindle styles avoid CSS the Kindle renderer doesn't support
+    let css = try!(helpers::file::get_file_content("lib/kindle.css"));
+    for entry in entries.iter_mut().filter(|e| stylesheets.contains(&e.name)) {
+        entry.data = css.clone().into_bytes();
+    }
+
+    // Next to the output, so kindlegen's file can be moved there. Without the
+    // date, it isn't listed as a release if it is left over.
+    let kindle_epub = format!("dist/{}-kindle.epub", prefix);
+    try!(epub::write_entries(&kindle_epub, &entries));
+
+    let converted = convert_all(Path::new(&kindle_epub), prefix, kindle);
+    try!(fs::remove_file(&kindle_epub));
+    converted
+}
+
+fn convert_all(epub: &Path, prefix: &str, kindle: &Kindle) -> Result<(), Box<Error>> {
+    for format in &kindle.formats {
+        let output = format!("dist/{}-{}.{}", prefix, options::RELEASE_DATE, format.extension());
+        try!(kindle.convert(epub, Path::new(&output)));
+        try!(validate(Path::new(&output)));
+        println!("[✓] {}", format.extension().to_uppercase());
+    }
+    Ok(())
+}
+
+#[test]
+fn kindle_settings() {
+    let kindle = Kindle::new("ebook-convert", "mobi, azw3").unwrap();
+    assert_eq!(kindle.formats, vec![KindleFormat::Mobi, KindleFormat::Azw3]);
+
+    assert!(Kindle::new("ebook-convert", "kf8").is_err());
+    assert!(Kindle::new("/opt/kindlegen", "azw3").is_err());
+    assert!(Kindle::new("/opt/kindlegen", "mobi").is_ok());
+}
@@ -0,0 +1,66 @@
+//! Where relative links to other Rust documentation point to
+//!
+//! The books link to the API docs and other books with relative URLs like
+//! `../std/vec/struct.Vec.html`. By default, these point to the latest docs on
+//! doc.rust-lang.org. To link to the docs of a specific Rust version or to
+//! other locations, use the meta data file:
+//!
+//! ```yaml
+//! rust-version: "1.12.0"
+//! link-bases:
+//!   ../std: https://static.rust-lang.org/doc/std
+//! ```
+
+use std::error::Error;
+use rustc_serialize::json::Json;
+
+use convert_book::options;
+use helpers::normalize::LinkBases;
+
+/// Get the base URL for each relative link prefix, longest prefix first
+pub fn get_link_bases(meta: &Json) -> Result<LinkBases, Box<Error>> {
+    let version = match meta.find("rust-version") {
+        Some(&Json::String(ref version)) => format!("/{}", version),
+        Some(&Json::Null) | None => String::new(),
+        Some(_) => return Err(From::from("`rust-version` needs to be a string, e.g. \"1.12.0\"")),
+    };
+
+    let mut bases: LinkBases = options::DOC_LINK_PREFIXES.iter()
+        .map(|name| {
+            (format!("../{}", name), format!("{}{}/{}", options::DOC_BASE_URL, version, name))
+        })
+        .collect();
+
+    match meta.find("link-bases") {
+        Some(&Json::Object(ref custom)) => {
+            for (prefix, base) in custom {
+                let base = try!(base.as_string()
+                                    .ok_or_else(|| format!("Link base for `{}` needs to be a URL", prefix)));
+                bases.retain(|&(ref p, _)| p != prefix);
+                bases.push((prefix.clone(), base.trim_right_matches('/').to_owned()));
+            }
+        }
+        Some(&Json::Null) | None => {}
+        Some(_) => return Err(From::from("`link-bases` needs to map prefixes to URLs")),
+    }
+
+    // `../std-foo` should not be matched by `../std`
+    bases.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+
+    Ok(bases)
+}
+
+#[test]
+fn pinned_rust_version() {
+    use helpers::yaml::parse_yaml;
+
+    let meta = parse_yaml("rust-version: \"1.12.0\"\nlink-bases:\n  ../std/io: http://example.com/io/\n")
+                   .unwrap();
+    let bases = get_link_bases(&meta).unwrap();
+
+    let position = |prefix: &str| bases.iter().position(|&(ref p, _)| p == prefix).unwrap();
+
+    assert_eq!(bases[position("../std/io")].1, "http://example.com/io");
+    assert_eq!(bases[position("../std")].1, "https://doc.rust-lang.org/1.12.0/std");
+    assert!(position("../std/io") < position("../std"));
+}
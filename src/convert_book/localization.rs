@@ -0,0 +1,216 @@
+//! Languages we can render books in
+//!
+//! The language of a book is taken from the `language` field of its meta data
+//! file. It decides which fonts and hyphenation patterns LaTeX uses, how
+//! quotes look in the PDF and how generated headlines are called.
+
+use std::ascii::AsciiExt;
+use std::error::Error;
+use rustc_serialize::json::Json;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Language {
+    /// BCP 47 language tag, e.g. `zh-CN`
+    pub code: &'static str,
+    /// Name of the language in that language, used in the download index
+    pub name: &'static str,
+    /// Polyglossia language name, sets hyphenation patterns and quote style
+    pub polyglossia: Option<&'static str>,
+    /// Font for CJK characters
+    pub cjk_font: Option<&'static str>,
+    pub introduction: &'static str,
+    pub table_of_contents: &'static str,
+    /// Headline of the lists of external links
+    pub links: &'static str,
+    pub index: &'static str,
+    pub glossary: &'static str,
+    /// Introduces the chapters a glossary term is used in
+    pub used_in: &'static str,
+    /// Label of numbered code listings, e.g. "Listing 4-2"
+    pub listing: &'static str,
+    /// Put before page numbers of cross references in the PDF
+    pub see_page: &'static str,
+}
+
+pub const DEFAULT_LANGUAGE: &'static str = "en";
+
+pub const LANGUAGES: &'static [Language] = &[
+    Language {
+        code: "en",
+        name: "English",
+        polyglossia: None,
+        cjk_font: None,
+        introduction: "Introduction",
+        table_of_contents: "Table of Contents",
+        links: "Links",
+        index: "Index",
+        glossary: "Glossary",
+        used_in: "Used in",
+        listing: "Listing",
+        see_page: "see page",
+    },
+    Language {
+        code: "de",
+        name: "Deutsch",
+        polyglossia: Some("german"),
+        cjk_font: None,
+        introduction: "Einleitung",
+        table_of_contents: "Inhaltsverzeichnis",
+        links: "Links",
+        index: "Stichwortverzeichnis",
+        glossary: "Glossar",
+        used_in: "Verwendet in",
+        listing: "Listing",
+        see_page: "siehe Seite",
+    },
+    Language {
+        code: "es",
+        name: "Español",
+        polyglossia: Some("spanish"),
+        cjk_font: None,
+        introduction: "Introducción",
+        table_of_contents: "Índice",
+        links: "Enlaces",
+        index: "Índice alfabético",
+        glossary: "Glosario",
+        used_in: "Usado en",
+        listing: "Listado",
+        see_page: "véase la página",
+    },
+    Language {
+        code: "fr",
+        name: "Français",
+        polyglossia: Some("french"),
+        cjk_font: None,
+        introduction: "Introduction",
+        table_of_contents: "Table des matières",
+        links: "Liens",
+        index: "Index",
+        glossary: "Glossaire",
+        used_in: "Utilisé dans",
+        listing: "Listing",
+        see_page: "voir page",
+    },
+    Language {
+        code: "it",
+        name: "Italiano",
+        polyglossia: Some("italian"),
+        cjk_font: None,
+        introduction: "Introduzione",
+        table_of_contents: "Indice",
+        links: "Collegamenti",
+        index: "Indice analitico",
+        glossary: "Glossario",
+        used_in: "Usato in",
+        listing: "Listato",
+        see_page: "vedi pagina",
+    },
+    Language {
+        code: "ja",
+        name: "日本語",
+        polyglossia: None,
+        cjk_font: Some("IPAexMincho"),
+        introduction: "はじめに",
+        table_of_contents: "目次",
+        links: "リンク",
+        index: "索引",
+        glossary: "用語集",
+        used_in: "使用箇所",
+        listing: "リスト",
+        see_page: "参照 p.",
+    },
+    Language {
+        code: "ko",
+        name: "한국어",
+        polyglossia: None,
+        cjk_font: Some("Noto Serif CJK KR"),
+        introduction: "소개",
+        table_of_contents: "목차",
+        links: "링크",
+        index: "찾아보기",
+        glossary: "용어집",
+        used_in: "사용된 곳",
+        listing: "예제",
+        see_page: "참조 p.",
+    },
+    Language {
+        code: "zh-CN",
+        name: "简体中文",
+        polyglossia: None,
+        cjk_font: Some("Noto Serif CJK SC"),
+        introduction: "介绍",
+        table_of_contents: "目录",
+        links: "链接",
+        index: "索引",
+        glossary: "术语表",
+        used_in: "用于",
+        listing: "代码清单",
+        see_page: "参见 p.",
+    },
+    Language {
+        code: "zh-TW",
+        name: "繁體中文",
+        polyglossia: None,
+        cjk_font: Some("Noto Serif CJK TC"),
+        introduction: "介紹",
+        table_of_contents: "目錄",
+        links: "連結",
+        index: "索引",
+        glossary: "術語表",
+        used_in: "用於",
+        listing: "程式碼清單",
+        see_page: "參見 p.",
+    },
+];
+
+/// Find a language by its tag. Tags we don't know exactly fall back to their
+/// primary language, e.g. `de-AT` to `de`.
+pub fn find_language(code: &str) -> Option<&'static Language> {
+    let primary = code.split('-').next().unwrap_or(code);
+
+    LANGUAGES.iter()
+             .find(|lang| lang.code.eq_ignore_ascii_case(code))
+             .or_else(|| LANGUAGES.iter().find(|lang| lang.code.eq_ignore_ascii_case(primary)))
+}
+
+/// Get the language given in the `language` field of a book's meta data
+pub fn get_language(meta: &Json) -> Result<&'static Language, Box<Error>> {
+    let code = meta.find("language").and_then(Json::as_string).unwrap_or(DEFAULT_LANGUAGE);
+    let lang = try!(find_language(code).ok_or_else(|| format!("Unsupported language `{}`", code)));
+    Ok(lang)
+}
+
+impl Language {
+    pub fn is_default(&self) -> bool {
+        self.code == DEFAULT_LANGUAGE
+    }
+
+    /// Additional YAML meta data block with the language specific template
+    /// variables. Pandoc uses the first value it finds, so values in the
+    /// book's own meta data take precedence.
+    pub fn meta_data(&self) -> String {
+        let mut meta = String::from("---\n");
+
+        meta.push_str(&format!("lang: \"{}\"\n", self.code));
+        meta.push_str(&format!("toc-title: \"{}\"\n", self.table_of_contents));
+        meta.push_str(&format!("index-title: \"{}\"\n", self.index));
+        meta.push_str(&format!("see-page: \"{}\"\n", self.see_page));
+        if let Some(polyglossia) = self.polyglossia {
+            meta.push_str(&format!("polyglossia-lang: \"{}\"\n", polyglossia));
+        }
+        if let Some(font) = self.cjk_font {
+            meta.push_str(&format!("cjkmainfont: \"{}\"\n", font));
+        }
+
+        meta.push_str("...\n");
+        meta
+    }
+}
+
+#[test]
+fn language_lookup() {
+    assert_eq!(find_language("ja").unwrap().code, "ja");
+    assert_eq!(find_language("zh-cn").unwrap().code, "zh-CN");
+    assert_eq!(find_language("de-AT").unwrap().code, "de");
+    assert_eq!(find_language("tlh"), None);
+}
@@ -0,0 +1,166 @@
+//! Machine-readable lists of the releases, next to the download page
+//!
+//! `manifest.json` lists every file of every release, `releases.atom` has an
+//! entry per release, so tools can find new releases without parsing HTML.
+
+use std::collections::BTreeMap;
+use rustc_serialize::json::Json;
+
+use convert_book::index::{Download, Releases, escape_html, format_label};
+use convert_book::options;
+
+fn media_type(format: &str) -> &'static str {
+    match format.rsplit('.').next().unwrap_or("") {
+        "pdf" => "application/pdf",
+        "epub" => "application/epub+zip",
+        "mobi" => "application/x-mobipocket-ebook",
+        "azw3" => "application/vnd.amazon.ebook",
+        "html" => "text/html",
+        "md" => "text/markdown",
+        "tex" => "application/x-tex",
+        _ => "application/octet-stream",
+    }
+}
+
+fn optional(value: Option<&str>) -> Json {
+    value.map_or(Json::Null, |v| Json::String(v.to_owned()))
+}
+
+fn file_json(file: &Download) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("format".to_owned(), Json::String(file.format.clone()));
+    object.insert("file_name".to_owned(), Json::String(file.file_name.clone()));
+    object.insert("url".to_owned(), Json::String(format!("{}{}", options::DOWNLOAD_URL, file.file_name)));
+    object.insert("size".to_owned(), Json::U64(file.size));
+    object.insert("sha256".to_owned(), Json::String(file.sha256.clone()));
+    object.insert("pages".to_owned(), file.pages.map_or(Json::Null, |p| Json::U64(p as u64)));
+    Json::Object(object)
+}
+
+/// All books with their releases (newest first) and files
+pub fn render_manifest(releases: &Releases) -> String {
+    let mut books = vec![];
+
+    for (book, releases_of_book) in releases.by_book() {
+        let info = &releases.books[book];
+        let mut object = BTreeMap::new();
+        object.insert("book".to_owned(), Json::String(book.to_owned()));
+        object.insert("title".to_owned(), Json::String(info.title.clone()));
+        object.insert("language".to_owned(), Json::String(releases_of_book[0].1[0].language.clone()));
+        object.insert("latest".to_owned(), Json::String(releases_of_book[0].0.to_owned()));
+        // Stable URLs of the newest files, by format
+        let aliases = releases_of_book[0].1.iter()
+            .filter_map(|file| {
+                releases.alias(file).map(|alias| {
+                    (file.format.clone(), Json::String(format!("{}{}", options::DOWNLOAD_URL, alias)))
+                })
+            })
+            .collect();
+        object.insert("latest_urls".to_owned(), Json::Object(aliases));
+        object.insert("releases".to_owned(), Json::Array(releases_of_book.iter().map(|&(date, ref files)| {
+            let mut release = BTreeMap::new();
+            release.insert("date".to_owned(), Json::String(date.to_owned()));
+            release.insert("revision".to_owned(), optional(releases.revision(book, date)));
+            release.insert("files".to_owned(), Json::Array(files.iter().map(|f| file_json(f)).collect()));
+            Json::Object(release)
+        }).collect()));
+        books.push(Json::Object(object));
+    }
+
+    let mut manifest = BTreeMap::new();
+    manifest.insert("books".to_owned(), Json::Array(books));
+    format!("{}\n", Json::Object(manifest).pretty())
+}
+
+/// An Atom feed with an entry for each release, newest first
+pub fn render_feed(releases: &Releases) -> String {
+    let mut entries: Vec<(&str, &str, Vec<&Download>)> = releases.by_book()
+        .into_iter()
+        .flat_map(|(book, releases_of_book)| {
+            releases_of_book.into_iter().map(move |(date, files)| (book, date, files))
+        })
+        .collect();
+    entries.sort_by(|a, b| (b.1, a.0).cmp(&(a.1, b.0)));
+
+    let mut feed = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                                 <feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    feed.push_str(&format!("  <title>{} E-Books</title>\n", escape_html(&releases.title())));
+    feed.push_str(&format!("  <id>{}</id>\n", options::DOWNLOAD_URL));
+    feed.push_str(&format!("  <link href=\"{}\"/>\n", options::DOWNLOAD_URL));
+    feed.push_str(&format!("  <link rel=\"self\" href=\"{}releases.atom\"/>\n", options::DOWNLOAD_URL));
+    feed.push_str(&format!("  <updated>{}T00:00:00Z</updated>\n", entries.first().map_or("1970-01-01", |e| e.1)));
+
+    for (book, date, files) in entries {
+        let info = &releases.books[book];
+        feed.push_str("  <entry>\n");
+        feed.push_str(&format!("    <title>{} ({})</title>\n", escape_html(&info.title), date));
+        feed.push_str(&format!("    <id>{}#{}-{}</id>\n", options::DOWNLOAD_URL, book, date));
+        feed.push_str(&format!("    <updated>{}T00:00:00Z</updated>\n", date));
+        feed.push_str(&format!("    <author><name>{}</name></author>\n",
+                               escape_html(info.author.as_ref().unwrap_or(&info.title))));
+        feed.push_str(&format!("    <link rel=\"alternate\" href=\"{}#{}\"/>\n", options::DOWNLOAD_URL, book));
+
+        let mut summary = vec![];
+        if let Some(revision) = releases.revision(book, date) {
+            summary.push(format!("Source revision {}", revision));
+        }
+        for file in files {
+            feed.push_str(&format!("    <link rel=\"enclosure\" href=\"{}{}\" type=\"{}\" length=\"{}\" title=\"{}\"/>\n",
+                                   options::DOWNLOAD_URL,
+                                   file.file_name,
+                                   media_type(&file.format),
+                                   file.size,
+                                   format_label(&file.format)));
+            summary.push(format!("{}: SHA-256 {}", file.file_name, file.sha256));
+        }
+        feed.push_str(&format!("    <summary>{}</summary>\n", escape_html(&summary.join("\n"))));
+        feed.push_str("  </entry>\n");
+    }
+
+    feed.push_str("</feed>\n");
+    feed
+}
+
+#[test]
+fn release_lists() {
+    use convert_book::index::BookInfo;
+
+    let download = |date: &str, format: &str| {
+        Download {
+            book: "trpl".into(),
+            language: "en".into(),
+            date: date.into(),
+            format: format.into(),
+            file_name: format!("trpl-{}.{}", date, format),
+            size: 42,
+            sha256: "abc".into(),
+            pages: None,
+        }
+    };
+    let mut books = BTreeMap::new();
+    books.insert("trpl".to_owned(),
+                 BookInfo { title: "The Book".into(), author: None, homepage: None });
+    let mut revisions = BTreeMap::new();
+    revisions.insert(("trpl".to_owned(), "2016-10-01".to_owned()), "deadbeef".to_owned());
+    let releases = Releases {
+        downloads: vec![download("2016-10-01", "epub"), download("2016-09-01", "epub")],
+        books: books,
+        revisions: revisions,
+        archive: false,
+        aliases: vec!["trpl-latest.epub".to_owned()].into_iter().collect(),
+    };
+
+    let manifest = Json::from_str(&render_manifest(&releases)).unwrap();
+    let book = &manifest["books"][0];
+    assert_eq!(book["latest"], Json::String("2016-10-01".into()));
+    assert_eq!(book["latest_urls"]["epub"],
+               Json::String(format!("{}trpl-latest.epub", options::DOWNLOAD_URL)));
+    assert_eq!(book["releases"][0]["revision"], Json::String("deadbeef".into()));
+    assert_eq!(book["releases"][1]["revision"], Json::Null);
+    assert_eq!(book["releases"][1]["files"][0]["size"], Json::U64(42));
+
+    let feed = render_feed(&releases);
+    assert!(feed.contains("<title>The Book (2016-10-01)</title>"));
+    assert!(feed.find("2016-10-01</id>") < feed.find("2016-09-01</id>"));
+    assert!(feed.contains("type=\"application/epub+zip\" length=\"42\" title=\"EPUB\""));
+}
@@ -1,8 +1,10 @@
-use std::path::Path;
 use std::error::Error;
+use std::collections::BTreeMap;
 use regex::Regex;
 
 use helpers::*;
+use helpers::normalize_code_blocks::HiddenCode;
+use convert_book::Book;
 
 /// Poor man's progress indicator
 macro_rules! put {
@@ -22,6 +24,19 @@ struct Chapter {
     headline: String,
 }
 
+/// The name of a file without path and extension, used in section IDs
+pub fn file_id(filename: &str) -> String {
+    let filename_pattern = Regex::new(r"(?x)
+        ^
+        (?P<path>(.*)/)?
+        (?P<name>(.*?))
+        (?P<ext>\.(\w*))?
+        $
+    ").unwrap();
+
+    filename_pattern.captures(filename).unwrap().name("name").unwrap().to_owned()
+}
+
 fn get_chapters(toc: &str) -> Vec<Chapter> {
     let toc_pattern = Regex::new(r"(?x)
         (?P<indent>\s*?)
@@ -34,21 +49,11 @@ fn get_chapters(toc: &str) -> Vec<Chapter> {
         \)
     ").unwrap();
 
-    let filename_pattern = Regex::new(r"(?x)
-        ^
-        (?P<path>(.*)/)?
-        (?P<name>(.*?))
-        (?P<ext>\.(\w*))?
-        $
-    ").unwrap();
-
     toc.lines()
     .filter_map(|l| toc_pattern.captures(l))
     .map(|link| {
         let level = if link.name("indent").unwrap().chars().count() == 0 { "#" } else { "##" };
-        let id = filename_pattern.captures(
-            link.name("filename").unwrap()
-        ).unwrap().name("name").unwrap();
+        let id = file_id(link.name("filename").unwrap());
 
         let headline = format!(
             "{level} {name} {{#sec--{link}}}\n",
@@ -63,40 +68,86 @@ fn get_chapters(toc: &str) -> Vec<Chapter> {
     .collect::<Vec<Chapter>>()
 }
 
-pub fn to_single_file(src_path: &Path, meta: &str) -> Result<String, Box<Error>> {
+pub fn to_single_file(source: &Book, hidden_code: HiddenCode) -> Result<String, Box<Error>> {
+    let src_path = source.src_path;
+
     put!("Reading book");
 
     let toc = try!(file::get_file_content(&src_path.join("SUMMARY.md")));
+    let chapters = get_chapters(&toc);
     put!(".");
 
     let mut book = String::new();
+    // Links to the explicit IDs of each chapter and the IDs
+    let mut explicit_ids = BTreeMap::new();
 
-    book.push_str(meta);
+    book.push_str(&source.meta_data);
     book.push_str("\n");
 
-    {
-        // Readme ~ "Getting Started"
-        let file = try!(file::get_file_content(&src_path.join("README.md")));
+    for part in &source.front_matter {
+        // Don't add it twice if it's also listed as a chapter
+        if chapters.iter().any(|chapter| chapter.file == part.file) {
+            continue;
+        }
+
+        let path = src_path.join(&part.file);
+        if part.optional && !path.exists() {
+            continue;
+        }
+
+        let file = try!(file::get_file_content(&path));
+        let title = part.title(remove_file_title::get_file_title(&file).as_ref().map(|t| &t[..]),
+                               source.language);
+
+        for id in adjust_header_ids::explicit_ids(&file) {
+            explicit_ids.insert(adjust_header_ids::section_id(&file_id(&part.file), &id), id);
+        }
         let mut content = try!(adjust_header_level::adjust_header_level(&file, 1));
         content = try!(remove_file_title::remove_file_title(&content));
-        content = try!(adjust_reference_names::adjust_reference_name(&content, "readme"));
-        content = try!(normalize::normalize(&content));
+        content = try!(adjust_header_ids::adjust_header_ids(&content, &file_id(&part.file)));
+        content = try!(adjust_reference_names::adjust_reference_name(&content, &part.file));
+        content = try!(normalize::normalize(&content, hidden_code, &source.link_bases));
 
         put!(".");
 
         book.push_str("\n\n");
-        book.push_str("# Introduction");
-        book.push_str("\n\n");
+        book.push_str(&format!(
+            "# {title} {{#sec--{id}{unnumbered}}}\n",
+            title = title,
+            id = file_id(&part.file),
+            unnumbered = if part.numbered { "" } else { " .unnumbered" }
+        ));
+        book.push_str("\n");
         book.push_str(&content);
     }
 
-    for chapter in &get_chapters(&toc) {
+    let mut listings = listings::Numbering {
+        chapter: 0,
+        count: 0,
+        label: source.language.listing,
+        numbers: BTreeMap::new(),
+    };
+
+    for chapter in &chapters {
         let file = try!(file::get_file_content(&src_path.join(&chapter.file)));
 
+        // Listings are numbered per top-level chapter
+        if chapter.headline.starts_with("# ") {
+            listings.chapter += 1;
+            listings.count = 0;
+        }
+
+        for id in adjust_header_ids::explicit_ids(&file) {
+            explicit_ids.insert(adjust_header_ids::section_id(&file_id(&chapter.file), &id), id);
+        }
         let mut content = try!(adjust_header_level::adjust_header_level(&file, 3));
+        if source.listings {
+            content = listings::number_listings(&content, &file_id(&chapter.file), &mut listings);
+        }
         content = try!(remove_file_title::remove_file_title(&content));
+        content = try!(adjust_header_ids::adjust_header_ids(&content, &file_id(&chapter.file)));
         content = try!(adjust_reference_names::adjust_reference_name(&content, &chapter.file));
-        content = try!(normalize::normalize(&content));
+        content = try!(normalize::normalize(&content, hidden_code, &source.link_bases));
 
         put!(".");
 
@@ -106,6 +157,26 @@ pub fn to_single_file(src_path: &Path, meta: &str) -> Result<String, Box<Error>>
         book.push_str(&content);
     }
 
+    // Links and anchors can point to other chapters, so these need the whole book
+    book = adjust_header_ids::link_explicit_ids(&book, &explicit_ids);
+    book = anchors::anchors_to_identifiers(&book);
+
+    // Links to listings in later chapters need all of them numbered first
+    if source.listings {
+        book = listings::link_listings(&book, &listings);
+    }
+
+    if let Some(ref glossary) = source.glossary {
+        let headings = glossary::Headings {
+            glossary: source.language.glossary,
+            used_in: source.language.used_in,
+        };
+        book = glossary::link_glossary(&book,
+                                       &glossary.entries,
+                                       glossary.chapter.as_ref().map(|c| &c[..]),
+                                       &headings);
+    }
+
     put!(" done.\n");
 
     Ok(book)
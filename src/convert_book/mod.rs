@@ -1,45 +1,330 @@
 //! Tools to compile the book
 
+pub mod check;
+pub mod epub;
+pub mod epub_check;
+pub mod front_matter;
+pub mod glossary;
 pub mod index;
+pub mod kindle;
+pub mod link_bases;
+pub mod localization;
+pub mod manifest;
 pub mod markdown;
 pub mod options;
+pub mod page_map;
 pub mod pandoc;
+pub mod pdf;
+pub mod prune;
 
+use std::env;
 use std::path::Path;
+use std::process::Command;
 use std::error::Error;
+use rustc_serialize::json::Json;
 use helpers;
+use helpers::normalize_code_blocks::{HiddenCode, break_code_blocks, gray_hidden_code};
 use convert_book::pandoc::save_as;
+use convert_book::localization::Language;
+use convert_book::front_matter::FrontMatter;
+use convert_book::kindle::Kindle;
+use convert_book::prune::Retention;
+use convert_book::epub::{EmbeddedFont, EpubSettings};
+use convert_book::pdf::PageProfile;
+use helpers::normalize::LinkBases;
+use helpers::link_notes::{LinkNotes, collect_link_notes};
+use helpers::index_terms::{IndexFormat, add_index};
+use helpers::raw_html::{UnknownHtml, translate_html};
+
+/// The last git commit that changed the book's sources or its meta data,
+/// listed in the release manifest. `None` if they aren't in a git repository
+/// (or have no commits), a `source-revision` in the meta data can be used then.
+fn source_revision(src_path: &Path, meta_file: &str) -> Option<String> {
+    // git runs in `src_path`, so the meta file needs an absolute path
+    let meta_file = match env::current_dir() {
+        Ok(dir) => dir.join(meta_file),
+        Err(_) => return None,
+    };
+
+    Command::new("git")
+        .arg("log").arg("-1").arg("--format=%H").arg("--").arg(".").arg(&meta_file)
+        .current_dir(src_path)
+        .output()
+        .ok()
+        .and_then(|output| if output.status.success() { String::from_utf8(output.stdout).ok() } else { None })
+        .map(|revision| revision.trim().to_owned())
+        .and_then(|revision| if revision.is_empty() { None } else { Some(revision) })
+}
+
+/// A book's source files and the settings from its meta data
+pub struct Book<'a> {
+    pub src_path: &'a Path,
+    /// The YAML meta data block we give to pandoc
+    pub meta_data: String,
+    pub language: &'static Language,
+    pub front_matter: Vec<FrontMatter>,
+    pub link_bases: LinkBases,
+    /// Where to list external links in the HTML and EPUB versions
+    pub link_notes: Option<LinkNotes>,
+    /// Keywords for the back-of-book index, if the book should have one
+    pub index_terms: Option<Vec<String>>,
+    pub glossary: Option<glossary::Glossary>,
+    /// Whether to number the code listings
+    pub listings: bool,
+    /// Family of the code font to embed in the EPUB, its files are only
+    /// looked up when rendering
+    pub epub_font: Option<String>,
+    pub obfuscate_fonts: bool,
+    pub pdfa: Option<pdf::PdfA>,
+}
+
+impl<'a> Book<'a> {
+    pub fn load(src_path: &'a Path, meta_file: &str) -> Result<Book<'a>, Box<Error>> {
+        let meta_data = try!(helpers::file::get_file_content(meta_file));
+        let meta = try!(helpers::yaml::parse_yaml(&meta_data));
+        let language = try!(localization::get_language(&meta));
+        let link_notes = match meta.find("link-notes").and_then(Json::as_string) {
+            Some(name) => {
+                Some(try!(LinkNotes::from_str(name)
+                              .ok_or_else(|| format!("Unknown `link-notes` value `{}`", name))))
+            }
+            None => None,
+        };
+        let index_terms = if meta.find("index").and_then(Json::as_boolean).unwrap_or(false) {
+            let terms = meta.find("index-terms").and_then(Json::as_array).map_or(&[][..], |t| t);
+            Some(try!(terms.iter()
+                           .map(|term| term.as_string().map(str::to_owned))
+                           .collect::<Option<Vec<_>>>()
+                           .ok_or("`index-terms` needs to be a list of strings")))
+        } else {
+            None
+        };
+        let epub_font = if meta.find("embed-monofont").and_then(Json::as_boolean).unwrap_or(false) {
+            let family = try!(meta.find("monofont")
+                                  .and_then(Json::as_string)
+                                  .ok_or("`embed-monofont` needs a `monofont`"));
+            Some(family.to_owned())
+        } else {
+            None
+        };
+
+        // A `source-revision` of the meta data is passed on as it is
+        let revision = match meta.find("source-revision") {
+            Some(_) => None,
+            None => source_revision(src_path, meta_file),
+        };
+
+        Ok(Book {
+            src_path: src_path,
+            meta_data: format!("{}\n{}{}{}",
+                               meta_data.replace("{release_date}", options::RELEASE_DATE),
+                               language.meta_data(),
+                               try!(pdf::colophon_meta_data(&meta)),
+                               revision.map_or(String::new(), |revision| {
+                                   format!("---\nsource-revision: \"{}\"\n...\n", revision)
+                               })),
+            language: language,
+            front_matter: try!(front_matter::get_front_matter(&meta)),
+            link_bases: try!(link_bases::get_link_bases(&meta)),
+            link_notes: link_notes,
+            index_terms: index_terms,
+            glossary: try!(glossary::get_glossary(&meta, src_path)),
+            listings: meta.find("listings").and_then(Json::as_boolean).unwrap_or(false),
+            epub_font: epub_font,
+            obfuscate_fonts: meta.find("obfuscate-fonts").and_then(Json::as_boolean).unwrap_or(false),
+            pdfa: try!(pdf::PdfA::from_meta(&meta)),
+        })
+    }
+
+    /// The whole book as one Markdown file
+    pub fn to_markdown(&self, hidden_code: HiddenCode) -> Result<String, Box<Error>> {
+        markdown::to_single_file(self, hidden_code)
+    }
+}
+
+/// Warn about raw HTML that doesn't make it into the PDF
+fn print_unknown_html(unknown: &[UnknownHtml]) {
+    for html in unknown {
+        println!("[!] {}: Raw HTML `{}` is dropped from the PDF", html.section, html.html);
+    }
+}
 
 /// Render book in different formats
-pub fn render_book(prefix: &str, src_path: &Path, meta_file: &str) -> Result<(), Box<Error>> {
-    let meta_data = try!(helpers::file::get_file_content(meta_file));
+///
+/// Books in languages other than English get the language tag added to their
+/// file names, e.g. `trpl-ja-2016-10-01.epub`.
+///
+/// `show_hidden_code` lists the formats (`md`, `html`, `epub`, `pdf` or `all`)
+/// that should contain the lines of Rust code that rustdoc usually hides.
+///
+/// A PDF is rendered for each of the `pdf_profiles`, the first one is used for
+/// the LaTeX file and the page numbers in the EPUB.
+///
+/// With `kindle`, the EPUB is also converted to Kindle formats. With `strict`,
+/// errors found by checking the EPUB fail the build.
+pub fn render_book(prefix: &str,
+                   src_path: &Path,
+                   meta_file: &str,
+                   show_hidden_code: &[String],
+                   pdf_profiles: &[&PageProfile],
+                   kindle: Option<&Kindle>,
+                   strict: bool)
+    -> Result<(), Box<Error>>
+{
+    let primary_profile = try!(pdf_profiles.first().ok_or("No PDF profile to render"));
+
+    let source = try!(Book::load(src_path, meta_file));
+    let language = source.language;
+
+    let prefix = &if language.is_default() {
+        prefix.to_owned()
+    } else {
+        format!("{}-{}", prefix, language.code)
+    };
+
+    let mut latex_options = format!("{} --variable release-id={}-{}",
+                                    options::LATEX,
+                                    prefix,
+                                    options::RELEASE_DATE);
+    if language.polyglossia.is_some() {
+        // Use the language's quote style
+        latex_options.push_str(" --variable csquotes");
+    }
+    if let Some(ref pdfa) = source.pdfa {
+        latex_options.push_str(&format!(" {}", pdfa.latex_options()));
+    }
+
+    let book = try!(source.to_markdown(HiddenCode::Remove));
+    let full_book = if show_hidden_code.is_empty() {
+        None
+    } else {
+        Some(try!(source.to_markdown(HiddenCode::Show)))
+    };
 
-    let book = try!(markdown::to_single_file(src_path,
-                                             &meta_data.replace("{release_date}",
-                                                                options::RELEASE_DATE)));
+    let book_for = |format: &str| -> &str {
+        let show = show_hidden_code.iter().any(|f| f == format || f == "all");
+        match full_book {
+            Some(ref book) if show => book,
+            _ => &book,
+        }
+    };
 
-    try!(helpers::file::write_string_to_file(&book,
+    let break_code = |book: &str, width: usize| -> Result<String, Box<Error>> {
+        break_code_blocks(book, width, options::CODE_CONTINUATION_MARKER)
+    };
+
+    // E-readers are often offline, so list the URLs of external links
+    let with_link_notes = |book: &str| -> Result<String, Box<Error>> {
+        match source.link_notes {
+            Some(placement) => collect_link_notes(book, placement, language.links),
+            None => Ok(book.to_owned()),
+        }
+    };
+
+    let with_index = |book: &str, format: IndexFormat| -> String {
+        match source.index_terms {
+            Some(ref terms) => {
+                add_index(book, terms, options::INDEX_STD_ITEMS, format, language.index)
+            }
+            None => book.to_owned(),
+        }
+    };
+
+    try!(helpers::file::write_string_to_file(&try!(break_code(book_for("md"),
+                                                              options::CODE_WIDTH_HTML)),
                                              &format!("dist/{}-{}.md",
                                                       prefix,
                                                       options::RELEASE_DATE)));
     println!("[✓] {}", "MD");
 
-    try!(save_as(&book, prefix, "html", options::HTML));
-    try!(save_as(&book, prefix, "epub", options::EPUB));
+    try!(save_as(&try!(break_code(&try!(with_link_notes(book_for("html"))),
+                                  options::CODE_WIDTH_HTML)),
+                 prefix,
+                 "html",
+                 options::HTML));
+    // LaTeX drops raw HTML and ignores the CSS graying out hidden code
+    let pdf_book = gray_hidden_code(&with_index(book_for("pdf"), IndexFormat::Latex));
+    let (pdf_book, unknown_html) = translate_html(&pdf_book);
+    print_unknown_html(&unknown_html);
 
-    let cc_book = helpers::convert_checkmarks::convert_checkmarks(&book);    
-    try!(save_as(&cc_book, prefix, "tex", options::LATEX));
+    let cc_book = helpers::convert_checkmarks::convert_checkmarks(&pdf_book);
+    try!(save_as(&try!(break_code(&cc_book, primary_profile.code_width)),
+                 prefix,
+                 "tex",
+                 &latex_options));
 
     let plain_book = helpers::remove_emojis::remove_emojis(&cc_book);
-    try!(save_as(&plain_book,
-                 prefix,
-                 "a4.pdf",
-                 &format!(r"{} --variable papersize=a4paper", options::LATEX)));
-    try!(save_as(&plain_book,
-                 prefix,
-                 "letter.pdf",
-                 &format!(r"{} --variable papersize=letterpaper",
-                          options::LATEX)));
+    for profile in pdf_profiles {
+        let mut profile_options = format!("{} {}", latex_options, profile.latex_options());
+        if profile.name == primary_profile.name {
+            profile_options.push_str(" --variable page-map");
+        }
+        try!(save_as(&try!(break_code(&plain_book, profile.code_width)),
+                     prefix,
+                     &format!("{}.pdf", profile.name),
+                     &profile_options));
+    }
+
+    // The EPUB's page list uses the pages of the first PDF
+    let page_map = try!(page_map::read_page_map(Path::new(&format!("dist/{}-{}.{}.pdf",
+                                                                    prefix,
+                                                                    options::RELEASE_DATE,
+                                                                    primary_profile.name))));
+    let epub_book = with_index(&try!(with_link_notes(book_for("epub"))), IndexFormat::Html);
+    let epub_book = try!(break_code(&epub_book, options::CODE_WIDTH_EPUB));
+    try!(save_as(&epub_book, prefix, "epub", options::EPUB));
+    let epub_path = format!("dist/{}-{}.epub", prefix, options::RELEASE_DATE);
+    let epub_font = match source.epub_font {
+        Some(ref family) => Some(try!(EmbeddedFont::find(family, source.obfuscate_fonts))),
+        None => None,
+    };
+    try!(epub::finish_epub(&epub_path,
+                           &EpubSettings {
+                               font: epub_font.as_ref(),
+                               page_map: page_map.as_ref(),
+                               glossary: source.glossary.as_ref().map(|glossary| {
+                                   glossary.chapter.as_ref().map_or("glossary", |chapter| &chapter[..])
+                               }),
+                               index: source.index_terms.as_ref().map(|_| "index"),
+                               language: language,
+                           }));
+    if !try!(epub_check::check_epub(&epub_path)) && strict {
+        return Err(From::from("The EPUB has errors"));
+    }
+    if let Some(kindle) = kindle {
+        try!(kindle::save_as_kindle(&epub_path, prefix, kindle));
+    }
+
+    // Stable names for linking to the current release
+    try!(index::update_latest_aliases("dist/", prefix, options::RELEASE_DATE));
+    println!("[✓] {}", "Latest");
+
+    Ok(())
+}
+
+/// Write the download page, its archive page, the manifest and the feed for
+/// the releases in `path`, which have to be updated whenever files are added
+/// to or removed from it
+pub fn render_downloads(path: &str, template: &str, retention: &Retention) -> Result<(), Box<Error>> {
+    let mut releases = try!(index::Releases::scan(path));
+
+    // Scripts and feed readers get all releases, only the page is shortened
+    let manifest = manifest::render_manifest(&releases);
+    try!(helpers::file::write_string_to_file(&manifest, &format!("{}manifest.json", path)));
+    let feed = manifest::render_feed(&releases);
+    try!(helpers::file::write_string_to_file(&feed, &format!("{}releases.atom", path)));
+    println!("[✓] {}", "Manifest");
+
+    releases.retain(retention);
+    let index = index::render_index(&releases, template);
+    try!(helpers::file::write_string_to_file(&index, &format!("{}index.html", path)));
+    if releases.archive {
+        let archive_path = format!("{}{}/", path, prune::ARCHIVE_DIR);
+        let archived = try!(index::Releases::scan(&archive_path));
+        let archive_index = index::render_index(&archived, template);
+        try!(helpers::file::write_string_to_file(&archive_index, &format!("{}index.html", archive_path)));
+    }
+    println!("[✓] {}", "Index");
 
     Ok(())
 }
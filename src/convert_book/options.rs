@@ -1,9 +1,34 @@
 pub const RELEASE_DATE: &'static str = "2016-10-01";
 
-pub const MARKDOWN: &'static str = "markdown+grid_tables+pipe_tables-simple_tables+raw_html+implicit_figures+footnotes+intraword_underscores+auto_identifiers-inline_code_attributes";
+/// Where the contents of `dist/` are published
+pub const DOWNLOAD_URL: &'static str = "http://killercup.github.io/trpl-ebook/";
 
-pub const HTML: &'static str = "--smart --normalize --standalone --self-contained --highlight-style=tango --table-of-contents --section-divs --template=lib/template.html --css=lib/pandoc.css --to=html5";
+/// Relative links like `../std/…` point to this site
+pub const DOC_BASE_URL: &'static str = "https://doc.rust-lang.org";
+pub const DOC_LINK_PREFIXES: &'static [&'static str] = &["std", "core", "reference", "rustc",
+                                                         "syntax", "book", "adv-book", "nomicon"];
 
-pub const EPUB: &'static str = "--smart --normalize --standalone --self-contained --highlight-style=tango --epub-stylesheet=lib/epub.css --table-of-contents";
+/// Std items code spans are indexed under, e.g. `Vec<T>` or `std::rc::Rc::new()`
+pub const INDEX_STD_ITEMS: &'static [&'static str] = &[
+    "Any", "Arc", "AsRef", "Borrow", "Box", "BTreeMap", "BTreeSet", "Cell", "Clone", "Copy",
+    "Cow", "Debug", "Default", "Deref", "DerefMut", "Display", "Drop", "Eq", "Error", "Fn",
+    "FnMut", "FnOnce", "From", "HashMap", "HashSet", "Hash", "Into", "IntoIterator", "Iterator",
+    "Mutex", "Option", "Ord", "PartialEq", "PartialOrd", "PhantomData", "Rc", "RefCell",
+    "Result", "RwLock", "Send", "Sized", "String", "Sync", "ToString", "Vec", "VecDeque", "Weak",
+];
+
+/// Marker at the start of continuation lines of code that was too long
+pub const CODE_CONTINUATION_MARKER: &'static str = "↳ ";
+
+/// Maximum line lengths of code (in monospace columns) for each format, the
+/// PDF's are set by its page profile
+pub const CODE_WIDTH_HTML: usize = 87;
+pub const CODE_WIDTH_EPUB: usize = 64;
+
+pub const MARKDOWN: &'static str = "markdown+grid_tables+pipe_tables-simple_tables+raw_html+implicit_figures+footnotes+intraword_underscores+auto_identifiers+tex_math_dollars-inline_code_attributes";
+
+pub const HTML: &'static str = "--smart --normalize --standalone --self-contained --highlight-style=tango --table-of-contents --section-divs --mathml --template=lib/template.html --css=lib/pandoc.css --to=html5";
+
+pub const EPUB: &'static str = "--smart --normalize --standalone --self-contained --highlight-style=tango --epub-stylesheet=lib/epub.css --table-of-contents --mathml --to=epub3";
 
 pub const LATEX: &'static str = "--smart --normalize --standalone --self-contained --highlight-style=tango --chapters --table-of-contents --template=lib/template.tex --latex-engine=xelatex --to=latex";
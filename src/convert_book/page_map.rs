@@ -0,0 +1,85 @@
+//! The pages of the PDF the labels are on
+//!
+//! With the `page-map` variable, `lib/template.tex` lists every label and its
+//! page in the PDF's info dictionary as `/PageMap (label page label page …)`.
+//! The EPUB uses it for a page list matching the printed book.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use flate2::read::ZlibDecoder;
+use regex::Regex;
+
+/// Labels and the pages they are on, in the order of the document
+pub type PageMap = Vec<(String, String)>;
+
+fn parse_page_map(entries: &str) -> PageMap {
+    let words: Vec<&str> = entries.split_whitespace().collect();
+    words.chunks(2)
+         .filter(|pair| pair.len() == 2 && pair[1] != "0")
+         .map(|pair| (pair[0].to_owned(), pair[1].to_owned()))
+         .collect()
+}
+
+/// The text of a PDF's content and of each of its compressed streams (like
+/// object streams, which can hold dictionaries such as the info dictionary)
+pub fn pdf_texts(content: &[u8]) -> Vec<String> {
+    let mut texts = vec![String::from_utf8_lossy(content).into_owned()];
+
+    let mut start = 0;
+    while let Some(offset) = find_bytes(&content[start..], b"stream") {
+        start += offset + b"stream".len();
+        start += content[start..].iter().take_while(|&&b| b == b'\r' || b == b'\n').count();
+
+        // Streams that aren't compressed (or where `stream` was part of
+        // `endstream`) fail right away
+        let mut inflated = vec![];
+        let _ = ZlibDecoder::new(&content[start..]).read_to_end(&mut inflated);
+        if !inflated.is_empty() {
+            texts.push(String::from_utf8_lossy(&inflated).into_owned());
+        }
+    }
+
+    texts
+}
+
+/// Find the page map in a PDF's content, which might be in a compressed
+/// object stream
+fn find_page_map(content: &[u8]) -> Option<PageMap> {
+    let page_map = Regex::new(r"/PageMap\s*\((?P<entries>[^)]*)\)").unwrap();
+
+    pdf_texts(content).iter()
+                      .filter_map(|text| page_map.captures(text))
+                      .map(|caps| parse_page_map(caps.name("entries").unwrap()))
+                      .next()
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Read the page map of the PDF at `path`, if it has one
+pub fn read_page_map(path: &Path) -> Result<Option<PageMap>, Box<Error>> {
+    let mut content = vec![];
+    try!(try!(File::open(path)).read_to_end(&mut content));
+    Ok(find_page_map(&content))
+}
+
+#[test]
+fn page_map_in_object_stream() {
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(b"<< /Producer (xdvipdfmx) /PageMap (sec--intro iii sec--ownership 42 missing 0 ) >>").unwrap();
+    let mut pdf = b"%PDF-1.5\n5 0 obj\n<< /Type /ObjStm /Filter /FlateDecode >>\nstream\r\n".to_vec();
+    pdf.extend(encoder.finish().unwrap());
+    pdf.extend(b"\nendstream\nendobj\n".iter());
+
+    assert_eq!(find_page_map(&pdf),
+               Some(vec![("sec--intro".to_owned(), "iii".to_owned()),
+                         ("sec--ownership".to_owned(), "42".to_owned())]));
+    assert_eq!(find_page_map(b"%PDF-1.5\n"), None);
+}
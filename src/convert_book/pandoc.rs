@@ -8,17 +8,26 @@ pub fn run(args: &str, input: &str) -> Result<String, Box<Error>> {
     shell_pipe::run("pandoc", args, input)
 }
 
-pub fn save_as(book: &str, prefix: &str, format: &str, opts: &str) -> Result<(), Box<Error>> {
-    let opts = format!("--from={markdown_opts} {opts} \
-                        --output=dist/{prefix}-{release_date}.{format}",
+/// Convert `book` to the file at `path`
+pub fn save_to(book: &str, path: &str, opts: &str) -> Result<(), Box<Error>> {
+    let opts = format!("--from={markdown_opts} {opts} --output={path}",
                        markdown_opts = options::MARKDOWN,
                        opts = opts,
-                       prefix = prefix,
-                       release_date = options::RELEASE_DATE,
-                       format = format);
+                       path = path);
 
     try!(run(&opts, &book));
 
+    Ok(())
+}
+
+pub fn save_as(book: &str, prefix: &str, format: &str, opts: &str) -> Result<(), Box<Error>> {
+    try!(save_to(book,
+                 &format!("dist/{prefix}-{release_date}.{format}",
+                          prefix = prefix,
+                          release_date = options::RELEASE_DATE,
+                          format = format),
+                 opts));
+
     println!("[✓] {}", format.to_ascii_uppercase());
 
     Ok(())
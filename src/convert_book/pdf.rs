@@ -0,0 +1,367 @@
+//! Settings of the PDF versions
+//!
+//! Each PDF is rendered for a page profile, e.g. A4 or a 6 inch e-reader, and
+//! named after it: `trpl-2016-10-01.a5.pdf`.
+//!
+//! Printed books get a colophon page with the `isbn`, `edition`, `publisher`
+//! and `colophon` of the meta data, if it has any of them.
+//!
+//! PDF/A is set with `pdfa: 2b` (part and conformance level) and
+//! `pdfa-color-profile`, an ICC profile the PDF declares as its output intent.
+//! `pdfa-output-condition` names the profile, it defaults to sRGB for RGB
+//! profiles.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use rustc_serialize::json::Json;
+
+/// A paper size and the layout that goes with it
+#[derive(Debug, PartialEq, Eq)]
+pub struct PageProfile {
+    /// Used in file names and `--pdf`
+    pub name: &'static str,
+    /// Shown on the download page, e.g. "PDF (A5)"
+    pub label: &'static str,
+    /// `geometry` options setting the paper size
+    pub paper: &'static str,
+    /// `geometry` options setting the margins
+    pub margins: &'static str,
+    /// 10pt, 11pt or 12pt
+    pub font_size: &'static str,
+    /// Maximum line length of code, in monospace columns
+    pub code_width: usize,
+    /// Different inner and outer margins and chapters starting on the right
+    pub two_sided: bool,
+    /// For printing: the paper is larger than the trimmed page (the `layout`
+    /// of `geometry`), which gets crop marks and this much bleed
+    pub bleed: Option<&'static str>,
+    /// Highlighting that works in grayscale and the colophon page
+    pub print: bool,
+}
+
+pub const PAGE_PROFILES: &'static [PageProfile] = &[
+    PageProfile {
+        name: "a4",
+        label: "A4",
+        paper: "a4paper",
+        margins: "scale=0.7",
+        font_size: "10pt",
+        code_width: 87,
+        two_sided: true,
+        bleed: None,
+        print: false,
+    },
+    PageProfile {
+        name: "letter",
+        label: "Letter",
+        paper: "letterpaper",
+        margins: "scale=0.7",
+        font_size: "10pt",
+        code_width: 89,
+        two_sided: true,
+        bleed: None,
+        print: false,
+    },
+    PageProfile {
+        name: "a5",
+        label: "A5",
+        paper: "a5paper",
+        margins: "inner=15mm,outer=12mm,top=15mm,bottom=18mm",
+        font_size: "10pt",
+        code_width: 70,
+        two_sided: true,
+        bleed: None,
+        print: false,
+    },
+    PageProfile {
+        name: "b5",
+        label: "B5",
+        paper: "b5paper",
+        margins: "inner=20mm,outer=15mm,top=20mm,bottom=22mm",
+        font_size: "10pt",
+        code_width: 82,
+        two_sided: true,
+        bleed: None,
+        print: false,
+    },
+    PageProfile {
+        name: "6x9",
+        label: "6×9 in",
+        paper: "paperwidth=6in,paperheight=9in",
+        margins: "inner=19mm,outer=13mm,top=16mm,bottom=19mm",
+        font_size: "10pt",
+        code_width: 70,
+        two_sided: true,
+        bleed: None,
+        print: false,
+    },
+    PageProfile {
+        name: "ereader",
+        label: "E-reader",
+        paper: "paperwidth=90mm,paperheight=120mm",
+        margins: "hmargin=4mm,top=4mm,bottom=8mm",
+        font_size: "10pt",
+        code_width: 48,
+        two_sided: false,
+        bleed: None,
+        print: false,
+    },
+    PageProfile {
+        name: "print",
+        label: "Print, 6×9 in",
+        paper: "paperwidth=178.4mm,paperheight=254.6mm,layoutwidth=6in,layoutheight=9in,layoutoffset=13mm",
+        margins: "inner=19mm,outer=13mm,top=16mm,bottom=19mm",
+        font_size: "10pt",
+        code_width: 70,
+        two_sided: true,
+        bleed: Some("3mm"),
+        print: true,
+    },
+];
+
+pub const DEFAULT_PAGE_PROFILES: &'static str = "a4,letter";
+
+pub fn find_profile(name: &str) -> Option<&'static PageProfile> {
+    PAGE_PROFILES.iter().find(|profile| profile.name == name)
+}
+
+/// Parse a comma separated list of profile names, e.g. `a4,ereader`
+pub fn page_profiles(names: &str) -> Result<Vec<&'static PageProfile>, Box<Error>> {
+    let profiles = try!(names.split(',')
+                             .map(|name| find_profile(name.trim()).ok_or(name.trim()))
+                             .collect::<Result<Vec<_>, _>>()
+                             .map_err(|name| {
+                                 let known: Vec<&str> = PAGE_PROFILES.iter().map(|p| p.name).collect();
+                                 format!("Unknown PDF profile `{}`, use one of {}", name, known.join(", "))
+                             }));
+    Ok(profiles)
+}
+
+impl PageProfile {
+    /// Pandoc options passing the profile to `lib/template.tex`
+    pub fn latex_options(&self) -> String {
+        let mut options = format!("--variable fontsize={}", self.font_size);
+        for option in self.paper.split(',').chain(self.margins.split(',')) {
+            options.push_str(&format!(" --variable geometry={}", option));
+        }
+        if self.two_sided {
+            options.push_str(" --variable classoption=twoside --variable classoption=openright");
+        } else {
+            options.push_str(" --variable classoption=oneside --variable classoption=openany");
+        }
+        if let Some(bleed) = self.bleed {
+            options.push_str(&format!(" --variable geometry=showcrop --variable bleed={}", bleed));
+        }
+        if self.print {
+            // Replaces the colorful `tango` style of `options::LATEX`
+            options.push_str(" --variable print --highlight-style=monochrome");
+        }
+        options
+    }
+}
+
+/// Whether `isbn` is a valid ISBN-10 or ISBN-13 (starting with the 978 or 979
+/// prefix), e.g. `978-3-16-148410-0`
+pub fn is_valid_isbn(isbn: &str) -> bool {
+    let chars: Vec<char> = isbn.chars().filter(|c| *c != '-' && *c != ' ').collect();
+    let digit = |c: char, position: usize| -> Option<u32> {
+        match c {
+            'X' | 'x' if chars.len() == 10 && position == 9 => Some(10),
+            c => c.to_digit(10),
+        }
+    };
+    let digits = match chars.iter().enumerate().map(|(i, c)| digit(*c, i)).collect::<Option<Vec<_>>>() {
+        Some(digits) => digits,
+        None => return false,
+    };
+
+    match digits.len() {
+        10 => digits.iter().enumerate().map(|(i, d)| (10 - i as u32) * d).sum::<u32>() % 11 == 0,
+        13 if digits[..3] == [9, 7, 8] || digits[..3] == [9, 7, 9] => {
+            digits.iter().enumerate().map(|(i, d)| if i % 2 == 0 { *d } else { 3 * d }).sum::<u32>() % 10 == 0
+        }
+        _ => false,
+    }
+}
+
+/// Meta data telling `lib/template.tex` to add a colophon page to printed
+/// books, if there is anything to put on it
+pub fn colophon_meta_data(meta: &Json) -> Result<String, Box<Error>> {
+    match meta.find("isbn") {
+        Some(&Json::String(ref isbn)) if !is_valid_isbn(isbn) => {
+            return Err(From::from(format!("`isbn` {} is no valid ISBN", isbn)));
+        }
+        // YAML reads ISBNs without dashes as numbers, dropping leading zeros
+        Some(&Json::String(_)) | None => {}
+        Some(isbn) => {
+            return Err(From::from(format!("`isbn` {} needs to be quoted, e.g. \"{}\"", isbn, isbn)));
+        }
+    }
+
+    let fields = ["isbn", "edition", "publisher", "colophon"];
+    if fields.iter().any(|field| meta.find(field).is_some()) {
+        Ok("---\ncolophon-page: true\n...\n".to_owned())
+    } else {
+        Ok(String::new())
+    }
+}
+
+/// The PDF/A standard the PDFs claim to follow
+#[derive(Debug, PartialEq, Eq)]
+pub struct PdfA {
+    /// 2 or 3, PDF/A-1 needs an older PDF version than XeLaTeX writes
+    pub part: u8,
+    /// `B` (visual appearance) or `U` (also Unicode text)
+    pub conformance: char,
+    pub color_profile: String,
+    /// Number of color components of the profile, e.g. 3 for RGB
+    pub components: u8,
+    /// Name of the profile's output condition, e.g. `sRGB IEC61966-2.1`
+    pub output_condition: String,
+}
+
+/// The number of color components of the ICC profile at `path`, from the
+/// color space in its header
+fn icc_components(path: &Path) -> Result<u8, Box<Error>> {
+    let mut header = [0u8; 20];
+    try!(try!(File::open(path)).read_exact(&mut header));
+
+    match &header[16..20] {
+        b"GRAY" => Ok(1),
+        b"RGB " => Ok(3),
+        b"CMYK" => Ok(4),
+        _ => {
+            Err(From::from(format!("Color profile `{}` is no gray, RGB or CMYK ICC profile", path.display())))
+        }
+    }
+}
+
+impl PdfA {
+    pub fn from_meta(meta: &Json) -> Result<Option<PdfA>, Box<Error>> {
+        let level = match meta.find("pdfa").and_then(Json::as_string) {
+            Some(level) => level.trim().to_uppercase(),
+            None => return Ok(None),
+        };
+
+        let mut chars = level.chars();
+        let (part, conformance) = match (chars.next(), chars.next(), chars.next()) {
+            (Some(part @ '2'), Some(conformance), None) |
+            (Some(part @ '3'), Some(conformance), None) if conformance == 'B' || conformance == 'U' => {
+                (part as u8 - b'0', conformance)
+            }
+            _ => return Err(From::from(format!("Unknown `pdfa` level `{}`, use e.g. `2b` or `3u`", level))),
+        };
+
+        let color_profile = try!(meta.find("pdfa-color-profile")
+                                     .and_then(Json::as_string)
+                                     .ok_or("PDF/A needs a `pdfa-color-profile` (an ICC file)"));
+        // The pandoc options are split at whitespace
+        if color_profile.contains(char::is_whitespace) {
+            return Err(From::from(format!("The path of the color profile `{}` can't contain spaces",
+                                          color_profile)));
+        }
+        if !Path::new(color_profile).is_file() {
+            return Err(From::from(format!("Color profile `{}` doesn't exist", color_profile)));
+        }
+        let components = try!(icc_components(Path::new(color_profile)));
+
+        let output_condition = match meta.find("pdfa-output-condition").and_then(Json::as_string) {
+            Some(condition) => condition.to_owned(),
+            None if components == 3 => "sRGB IEC61966-2.1".to_owned(),
+            None => return Err(From::from("PDF/A needs a `pdfa-output-condition` naming the color profile")),
+        };
+
+        Ok(Some(PdfA {
+            part: part,
+            conformance: conformance,
+            color_profile: color_profile.to_owned(),
+            components: components,
+            output_condition: output_condition,
+        }))
+    }
+
+    /// Pandoc options passing the settings to `lib/template.tex`
+    ///
+    /// The output condition is a hex string, which survives the options being
+    /// split at whitespace and LaTeX.
+    pub fn latex_options(&self) -> String {
+        let condition: String = self.output_condition.bytes().map(|byte| format!("{:02X}", byte)).collect();
+        format!("--variable pdfa-part={} --variable pdfa-conformance={} --variable pdfa-color-profile={} \
+                 --variable pdfa-components={} --variable pdfa-output-condition={}",
+                self.part,
+                self.conformance,
+                self.color_profile,
+                self.components,
+                condition)
+    }
+}
+
+#[test]
+fn page_profiles_options() {
+    let profiles = page_profiles("ereader, a4").unwrap();
+    assert_eq!(profiles[0].latex_options(),
+               "--variable fontsize=10pt --variable geometry=paperwidth=90mm \
+                --variable geometry=paperheight=120mm --variable geometry=hmargin=4mm \
+                --variable geometry=top=4mm --variable geometry=bottom=8mm \
+                --variable classoption=oneside --variable classoption=openany");
+    assert_eq!(profiles[1].name, "a4");
+    assert!(page_profiles("a4,a3").is_err());
+    assert!(find_profile("print").unwrap().latex_options().ends_with(
+        "--variable classoption=twoside --variable classoption=openright \
+         --variable geometry=showcrop --variable bleed=3mm --variable print --highlight-style=monochrome"));
+}
+
+#[test]
+fn isbns() {
+    assert!(is_valid_isbn("978-3-16-148410-0"));
+    assert!(is_valid_isbn("0-306-40615-2"));
+    assert!(is_valid_isbn("0-8044-2957-X"));
+    assert!(!is_valid_isbn("978-3-16-148410-1"));
+    assert!(!is_valid_isbn("ISBN 978-3-16-148410-0"));
+    // EANs of other products
+    assert!(!is_valid_isbn("4006381333931"));
+
+    let meta = |json: &str| Json::from_str(json).unwrap();
+    assert_eq!(colophon_meta_data(&meta(r#"{"isbn": "9783161484100"}"#)).unwrap(),
+               "---\ncolophon-page: true\n...\n");
+    assert_eq!(colophon_meta_data(&meta(r#"{"title": "Book"}"#)).unwrap(), "");
+    let unquoted = colophon_meta_data(&meta(r#"{"isbn": 9783161484100}"#)).unwrap_err();
+    assert!(unquoted.to_string().contains("needs to be quoted"));
+}
+
+#[test]
+fn pdfa_levels() {
+    use std::env;
+    use std::io::Write;
+
+    let pdfa = |json: &str| {
+        let json = json.replace("{dir}", &env::temp_dir().to_string_lossy());
+        PdfA::from_meta(&Json::from_str(&json).unwrap())
+    };
+    // ICC headers with the color space at byte 16
+    for &(name, space) in &[("rgb", b"RGB "), ("cmyk", b"CMYK")] {
+        let mut header = vec![0u8; 16];
+        header.extend(space.iter());
+        File::create(env::temp_dir().join(format!("pdfa-{}.icc", name))).unwrap().write_all(&header).unwrap();
+    }
+
+    assert_eq!(pdfa(r#"{"title": "Book"}"#).unwrap(), None);
+    let rgb = pdfa(r#"{"pdfa": "2b", "pdfa-color-profile": "{dir}/pdfa-rgb.icc"}"#).unwrap().unwrap();
+    assert_eq!((rgb.part, rgb.conformance, rgb.components), (2, 'B', 3));
+    // `sRGB IEC61966-2.1` in hex
+    assert!(rgb.latex_options().ends_with(
+        "--variable pdfa-components=3 --variable pdfa-output-condition=735247422049454336313936362D322E31"));
+    assert!(pdfa(r#"{"pdfa": "1b", "pdfa-color-profile": "{dir}/pdfa-rgb.icc"}"#).is_err());
+    assert!(pdfa(r#"{"pdfa": "3a", "pdfa-color-profile": "{dir}/pdfa-rgb.icc"}"#).is_err());
+    assert!(pdfa(r#"{"pdfa": "2u"}"#).is_err());
+    assert!(pdfa(r#"{"pdfa": "2b", "pdfa-color-profile": "Cargo.toml"}"#).is_err());
+    // CMYK profiles need their output condition named
+    assert!(pdfa(r#"{"pdfa": "2b", "pdfa-color-profile": "{dir}/pdfa-cmyk.icc"}"#).is_err());
+    let cmyk = pdfa(r#"{"pdfa": "2b", "pdfa-color-profile": "{dir}/pdfa-cmyk.icc",
+                        "pdfa-output-condition": "FOGRA39"}"#)
+                   .unwrap()
+                   .unwrap();
+    assert_eq!((cmyk.components, &cmyk.output_condition[..]), (4, "FOGRA39"));
+}
@@ -0,0 +1,104 @@
+//! Remove or archive old releases
+//!
+//! A release is kept if it is one of the `keep` newest releases of its book or
+//! not older than `since`. The newest release of a book is always kept.
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use regex::Regex;
+
+use convert_book::index::{Download, Releases};
+
+/// Sub directory of `dist/` old releases are moved to
+pub const ARCHIVE_DIR: &'static str = "archive";
+
+/// Which releases of each book to keep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Retention {
+    pub keep: Option<usize>,
+    pub since: Option<String>,
+}
+
+impl Retention {
+    pub fn new(keep: Option<usize>, since: Option<String>) -> Result<Retention, Box<Error>> {
+        let date = Regex::new(r"^\d{4}-\d{2}-\d{2}$").unwrap();
+        if let Some(ref since) = since {
+            if !date.is_match(since) {
+                return Err(From::from(format!("`{}` is no date like `2016-10-01`", since)));
+            }
+        }
+        Ok(Retention { keep: keep, since: since })
+    }
+
+    /// Whether to keep everything
+    pub fn keeps_all(&self) -> bool {
+        self.keep.is_none() && self.since.is_none()
+    }
+
+    /// Whether to keep the release from `date`, the `position`th newest of its
+    /// book (counting from 0)
+    pub fn keeps(&self, position: usize, date: &str) -> bool {
+        position == 0 || self.keeps_all() ||
+        self.keep.map_or(false, |keep| position < keep) ||
+        self.since.as_ref().map_or(false, |since| date >= &since[..])
+    }
+}
+
+/// The files of the releases `retention` doesn't keep
+pub fn pruned_files<'a>(releases: &'a Releases, retention: &Retention) -> Vec<&'a Download> {
+    releases.by_book()
+            .into_iter()
+            .flat_map(|(_, releases_of_book)| releases_of_book.into_iter().enumerate())
+            .filter(|&(position, (date, _))| !retention.keeps(position, date))
+            .flat_map(|(_, (_, files))| files)
+            .collect()
+}
+
+/// Delete the old releases in `path` or move them to its archive. With
+/// `dry_run`, only list them.
+pub fn prune(path: &str, retention: &Retention, archive: bool, dry_run: bool) -> Result<(), Box<Error>> {
+    let releases = try!(Releases::scan(path));
+    let archive_path = Path::new(path).join(ARCHIVE_DIR);
+
+    if archive && !dry_run {
+        try!(fs::create_dir_all(&archive_path));
+    }
+
+    for file in pruned_files(&releases, retention) {
+        let file_path = Path::new(path).join(&file.file_name);
+        if archive {
+            if !dry_run {
+                try!(fs::rename(&file_path, archive_path.join(&file.file_name)));
+            }
+            println!("[→] {} {}/{}", file.file_name, ARCHIVE_DIR, file.file_name);
+        } else {
+            if !dry_run {
+                try!(fs::remove_file(&file_path));
+            }
+            println!("[-] {}", file.file_name);
+        }
+    }
+
+    if dry_run {
+        println!("[i] Dry run, nothing was changed");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn retention() {
+    let keep_two = Retention::new(Some(2), None).unwrap();
+    assert!(keep_two.keeps(1, "2015-01-01"));
+    assert!(!keep_two.keeps(2, "2016-10-01"));
+
+    let since = Retention::new(None, Some("2016-01-01".into())).unwrap();
+    assert!(since.keeps(5, "2016-01-01"));
+    assert!(!since.keeps(5, "2015-12-31"));
+    // The newest release stays, however old it is
+    assert!(since.keeps(0, "2015-05-15"));
+
+    assert!(Retention::new(None, None).unwrap().keeps(100, "2015-01-01"));
+    assert!(Retention::new(None, Some("last week".into())).is_err());
+}
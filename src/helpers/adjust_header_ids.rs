@@ -0,0 +1,243 @@
+use std::error::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use regex::{Regex, Captures};
+
+const CODE_BLOCK_TOGGLE: &'static str = "```";
+
+/// The identifier pandoc's `auto_identifiers` extension generates for a
+/// heading (without the suffix it adds to duplicates).
+pub fn auto_identifier(title: &str) -> String {
+    let link = Regex::new(r"\[(?P<text>[^\]]*)\](\([^)]*\)|\[[^\]]*\])").unwrap();
+    let title = link.replace_all(title, "$text");
+
+    let id: String = title.chars()
+        .filter(|&c| c.is_alphanumeric() || c.is_whitespace() || c == '_' || c == '-' || c == '.')
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_lowercase()
+        .chars()
+        .skip_while(|c| !c.is_alphabetic())
+        .collect();
+
+    if id.is_empty() { "section".to_owned() } else { id }
+}
+
+/// The ID of a heading in the chapter with the given file ID, e.g.
+/// `sec--ownership--examples`
+pub fn section_id(file_id: &str, heading_id: &str) -> String {
+    format!("sec--{}--{}", file_id, heading_id)
+}
+
+/// Give all headings without an explicit ID one that is unique to this chapter
+/// and adjust the links to them.
+///
+/// Pandoc's automatic identifiers are unique in the whole book, so the second
+/// "Examples" section is `#examples-1`, no matter which chapter it is in. We
+/// use the automatic ID a heading would have in its own file instead, prefixed
+/// with the chapter (see `section_id`).
+pub fn adjust_header_ids(input: &str, file_id: &str) -> Result<String, Box<Error>> {
+    let headline_pattern = Regex::new(r"(?x)
+        ^
+        (?P<level>[\x23]+)          # A bunch of hash symbols
+        \s+
+        (?P<title>.+?)              # Title
+        \s*
+        (\{(?P<attrs>[^}]*)\})?     # Maybe some attributes
+        \s*
+        $
+    ").unwrap();
+
+    let local_link = Regex::new(r"\]\(#(?P<id>[^)\s]+)\)").unwrap();
+    let local_ref = Regex::new(r"^(?P<def>\[.+\]:\s+)#(?P<id>\S+)$").unwrap();
+
+    let mut in_code_block = false;
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    let mut ids = BTreeSet::new();
+    let mut lines = vec![];
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+
+        let new_line = match headline_pattern.captures(line) {
+            Some(ref headline) if !in_code_block &&
+                                  !headline.name("attrs").unwrap_or("").contains('#') => {
+                let id = auto_identifier(headline.name("title").unwrap());
+                let count = counts.entry(id.clone()).or_insert(0);
+                let id = if *count == 0 { id } else { format!("{}-{}", id, count) };
+                *count += 1;
+
+                let attrs = headline.name("attrs").map_or(String::new(), |a| format!(" {}", a.trim()));
+                let new_headline = format!(
+                    "{level} {title} {{#{id}{attrs}}}",
+                    level = headline.name("level").unwrap(),
+                    title = headline.name("title").unwrap(),
+                    id = section_id(file_id, &id),
+                    attrs = attrs
+                );
+                ids.insert(id);
+                new_headline
+            }
+            _ => line.to_owned(),
+        };
+
+        lines.push(new_line);
+    }
+
+    let rename = |caps: &Captures, template: &str| -> String {
+        let id = caps.name("id").unwrap();
+        if ids.contains(id) {
+            template.replace("{}", &section_id(file_id, id))
+        } else {
+            caps.at(0).unwrap().to_owned()
+        }
+    };
+
+    let mut in_code_block = false;
+
+    let output = lines.iter().fold(String::new(), |initial, line| {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+        if in_code_block {
+            return initial + line + "\n";
+        }
+
+        if let Some(caps) = local_ref.captures(line) {
+            let template = format!("{}#{{}}", caps.name("def").unwrap());
+            return initial + &rename(&caps, &template) + "\n";
+        }
+
+        let new_line = local_link.replace_all(line, |caps: &Captures| rename(caps, "](#{})"));
+        initial + &new_line + "\n"
+    });
+
+    Ok(output)
+}
+
+/// The IDs set explicitly in a chapter: those of headings with an `{#id}`,
+/// code blocks and `<span id="…">` anchors. `adjust_header_ids` leaves them as
+/// they are.
+pub fn explicit_ids(input: &str) -> Vec<String> {
+    let attributes = Regex::new(r"(^#+\s.*|^```\s*)\{[^}]*#(?P<id>[^\s}]+)[^}]*\}\s*$").unwrap();
+    let anchor = Regex::new(r#"<(span|a)\s+(id|name)=["'](?P<id>[^"']+)["']"#).unwrap();
+
+    let mut ids = vec![];
+    let mut in_code_block = false;
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+        if in_code_block && !line.starts_with(CODE_BLOCK_TOGGLE) {
+            continue;
+        }
+        let found = attributes.captures_iter(line).chain(anchor.captures_iter(line));
+        ids.extend(found.map(|caps| caps.name("id").unwrap().to_owned()));
+    }
+
+    ids
+}
+
+/// Links to other chapters point at `sec--{file}--{id}` (see `normalize`),
+/// which is wrong for explicit IDs. `explicit` maps these links to the IDs.
+pub fn link_explicit_ids(input: &str, explicit: &BTreeMap<String, String>) -> String {
+    let local_link = Regex::new(r"\]\(#(?P<id>[^)\s]+)\)").unwrap();
+    let local_ref = Regex::new(r"^(?P<def>\s{0,3}\[[^\]]+\]:\s+)#(?P<id>\S+)\s*$").unwrap();
+
+    let rename = |caps: &Captures, template: &str| -> String {
+        match explicit.get(caps.name("id").unwrap()) {
+            Some(id) => template.replace("{}", id),
+            None => caps.at(0).unwrap().to_owned(),
+        }
+    };
+
+    let mut output = String::with_capacity(input.len());
+    let mut in_code_block = false;
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+
+        if in_code_block {
+            output.push_str(line);
+        } else if let Some(caps) = local_ref.captures(line) {
+            let template = format!("{}#{{}}", caps.name("def").unwrap());
+            output.push_str(&rename(&caps, &template));
+        } else {
+            output.push_str(&local_link.replace_all(line, |caps: &Captures| rename(caps, "](#{})")));
+        }
+        output.push_str("\n");
+    }
+
+    output
+}
+
+#[test]
+fn pandoc_identifiers() {
+    assert_eq!(auto_identifier("Heading identifiers in HTML"), "heading-identifiers-in-html");
+    assert_eq!(auto_identifier("*Dogs*?--in *my* house?"), "dogs--in-my-house");
+    assert_eq!(auto_identifier("[HTML], [S5], or [RTF]?"), "html-s5-or-rtf");
+    assert_eq!(auto_identifier("3. Applications"), "applications");
+    assert_eq!(auto_identifier("33"), "section");
+    assert_eq!(auto_identifier("`Box<T>`"), "boxt");
+}
+
+#[test]
+fn header_id_adjustment() {
+    let chapter = "### Examples
+
+See [below](#examples-1) and [the code](#code-example).
+
+```rust
+# fn main() {}
+```
+
+### Examples {.unnumbered}
+
+#### Custom {#custom-id}
+
+[ref]: #examples
+";
+
+    let adjusted = "### Examples {#sec--testing--examples}
+
+See [below](#sec--testing--examples-1) and [the code](#code-example).
+
+```rust
+# fn main() {}
+```
+
+### Examples {#sec--testing--examples-1 .unnumbered}
+
+#### Custom {#custom-id}
+
+[ref]: #sec--testing--examples
+";
+
+    assert_eq!(adjust_header_ids(chapter, "testing").unwrap(), adjusted);
+}
+
+#[test]
+fn links_to_explicit_ids() {
+    use helpers::normalize::normalize;
+    use helpers::normalize_code_blocks::HiddenCode;
+
+    let traits = "## Objects {#custom-id}\n\n<span id=\"anchor\"></span>\n\n```{#code-x .rust}\n```\n";
+    let ids = explicit_ids(traits);
+    assert_eq!(ids, vec!["custom-id", "anchor", "code-x"]);
+
+    let explicit = ids.into_iter().map(|id| (section_id("traits", &id), id)).collect();
+    let ownership = normalize("See [objects](traits.html#custom-id), [this](traits.html#anchor) \
+                               and [auto IDs](traits.html#examples).\n",
+                              HiddenCode::Remove,
+                              &vec![])
+                        .unwrap();
+
+    assert_eq!(link_explicit_ids(&ownership, &explicit),
+               "See [objects](#custom-id), [this](#anchor) and [auto IDs](#sec--traits--examples).\n");
+}
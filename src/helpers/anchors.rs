@@ -0,0 +1,160 @@
+//! Turn empty `<span id="…"></span>` anchors into identifiers pandoc knows
+//!
+//! The LaTeX writer drops raw HTML, so links to these anchors don't work in
+//! the PDF. An anchor before a code block becomes the block's identifier, one
+//! before a heading is replaced by the heading's ID. Other anchors stay, and
+//! `raw_html::translate_html` turns them into LaTeX targets.
+
+use std::collections::BTreeMap;
+use regex::{Regex, Captures};
+
+const CODE_BLOCK_TOGGLE: &'static str = "```";
+
+/// The opening fence of a code block with the given identifier, e.g.
+/// ```` ```{#code-x .rust} ````
+fn fence_with_id(fence: &str, id: &str) -> String {
+    let info = fence[CODE_BLOCK_TOGGLE.len()..].trim();
+
+    if info.starts_with('{') {
+        format!("{}{{#{} {}", CODE_BLOCK_TOGGLE, id, info[1..].trim_left())
+    } else {
+        let classes: Vec<String> = info.split(|c| c == ',' || c == ' ')
+                                       .filter(|class| !class.is_empty())
+                                       .map(|class| format!(" .{}", class))
+                                       .collect();
+        format!("{}{{#{}{}}}", CODE_BLOCK_TOGGLE, id, classes.concat())
+    }
+}
+
+/// Move the anchors in `input` to the blocks following them and adjust the
+/// links to them.
+pub fn anchors_to_identifiers(input: &str) -> String {
+    let anchor = Regex::new(r#"^\s*<span id="(?P<id>[^"]+)">\s*</span>\s*$"#).unwrap();
+    let heading_id = Regex::new(r"^#+\s.*\{#(?P<id>[^\s}]+)[^}]*\}\s*$").unwrap();
+    let local_link = Regex::new(r"\]\(#(?P<id>[^)\s]+)\)").unwrap();
+    let local_ref = Regex::new(r"^(?P<def>\s{0,3}\[[^\]]+\]:\s+)#(?P<id>\S+)\s*$").unwrap();
+
+    let mut pending: Vec<String> = vec![];
+    // Anchors replaced by the ID of the block they belong to
+    let mut renamed = BTreeMap::new();
+    let mut lines: Vec<String> = vec![];
+    let mut in_code_block = false;
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+            if in_code_block && !pending.is_empty() {
+                let id = pending.remove(0);
+                for alias in pending.drain(..) {
+                    renamed.insert(alias, id.clone());
+                }
+                lines.push(fence_with_id(line, &id));
+                continue;
+            }
+        }
+        if in_code_block {
+            lines.push(line.to_owned());
+            continue;
+        }
+
+        if let Some(caps) = anchor.captures(line) {
+            pending.push(caps.name("id").unwrap().to_owned());
+            continue;
+        }
+        // The blank line after an anchor goes with it
+        if !pending.is_empty() && line.trim().is_empty() &&
+           lines.last().map_or(false, |l| l.trim().is_empty()) {
+            continue;
+        }
+
+        // Blank lines and the start of greyed out hidden code don't end the
+        // search for the block an anchor belongs to
+        if pending.is_empty() || line.trim().is_empty() || line.starts_with("\\begingroup") {
+            lines.push(line.to_owned());
+            continue;
+        }
+
+        if let Some(caps) = heading_id.captures(line) {
+            for alias in pending.drain(..) {
+                renamed.insert(alias, caps.name("id").unwrap().to_owned());
+            }
+        } else {
+            for id in pending.drain(..) {
+                lines.push(format!("<span id=\"{}\"></span>", id));
+                lines.push(String::new());
+            }
+        }
+        lines.push(line.to_owned());
+    }
+
+    for id in pending {
+        lines.push(format!("<span id=\"{}\"></span>", id));
+    }
+
+    let rename = |caps: &Captures, template: &str| -> String {
+        match renamed.get(caps.name("id").unwrap()) {
+            Some(id) => template.replace("{}", id),
+            None => caps.at(0).unwrap().to_owned(),
+        }
+    };
+
+    let mut output = String::with_capacity(input.len());
+    let mut in_code_block = false;
+
+    for line in lines {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+
+        if in_code_block {
+            output.push_str(&line);
+        } else if let Some(caps) = local_ref.captures(&line) {
+            let template = format!("{}#{{}}", caps.name("def").unwrap());
+            output.push_str(&rename(&caps, &template));
+        } else {
+            output.push_str(&local_link.replace_all(&line, |caps: &Captures| rename(caps, "](#{})")));
+        }
+        output.push_str("\n");
+    }
+
+    output
+}
+
+#[test]
+fn anchor_identifiers() {
+    let chapter = r#"See [this](#code-unwrap) and [that](#heading-anchor).
+
+<span id="code-unwrap"></span>
+
+```rust
+fn main() {}
+```
+
+<span id="heading-anchor"></span>
+
+### Result {#sec--errors--result}
+
+<span id="paragraph"></span>
+
+Some text.
+
+[ref]: #heading-anchor
+"#;
+
+    assert_eq!(anchors_to_identifiers(chapter), r#"See [this](#code-unwrap) and [that](#sec--errors--result).
+
+```{#code-unwrap .rust}
+fn main() {}
+```
+
+### Result {#sec--errors--result}
+
+<span id="paragraph"></span>
+
+Some text.
+
+[ref]: #sec--errors--result
+"#);
+    assert_eq!(fence_with_id("```{.hidden-code}", "x"), "```{#x .hidden-code}");
+    assert_eq!(fence_with_id("```rust,ignore", "x"), "```{#x .rust .ignore}");
+}
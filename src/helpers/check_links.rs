@@ -0,0 +1,207 @@
+//! Find broken links in the single Markdown file of a book
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ascii::AsciiExt;
+use regex::Regex;
+
+use helpers::adjust_header_ids::auto_identifier;
+
+const CODE_BLOCK_TOGGLE: &'static str = "```";
+
+/// A problem with a link, found in the section with the given ID
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BrokenLink {
+    /// Link to an ID that no heading or anchor has
+    MissingAnchor { section: String, target: String },
+    /// Reference-style link or footnote without definition
+    MissingReference { section: String, id: String },
+    /// Relative link to a file that is not part of the book
+    RelativeLink { section: String, target: String },
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LinkReport {
+    pub broken: Vec<BrokenLink>,
+    /// External URLs and how often they are used
+    pub external: BTreeMap<String, usize>,
+}
+
+fn is_external(target: &str) -> bool {
+    let scheme = Regex::new(r"^[a-zA-Z][a-zA-Z0-9+.-]*:").unwrap();
+    scheme.is_match(target)
+}
+
+/// Sort a link target into external URLs, links to anchors (to check later)
+/// and relative links we can't resolve.
+fn check_target(target: &str,
+                section: &str,
+                report: &mut LinkReport,
+                anchor_links: &mut Vec<(String, String)>) {
+    if target.starts_with('#') {
+        anchor_links.push((section.to_owned(), target[1..].to_owned()));
+    } else if is_external(target) {
+        *report.external.entry(target.to_owned()).or_insert(0) += 1;
+    } else {
+        report.broken.push(BrokenLink::RelativeLink {
+            section: section.to_owned(),
+            target: target.to_owned(),
+        });
+    }
+}
+
+/// Check all links in `input` (the whole book as one Markdown file).
+pub fn check_links(input: &str) -> LinkReport {
+    let heading = Regex::new(r"^#+\s+(?P<title>.+?)\s*(\{(?P<attrs>[^}]*)\})?\s*$").unwrap();
+    let explicit_id = Regex::new(r"#(?P<id>[^\s}]+)").unwrap();
+    let html_id = Regex::new(r#"\b(id|name)=["'](?P<id>[^"']+)["']"#).unwrap();
+    let inline_code = Regex::new(r"`+[^`]*`+").unwrap();
+    let inline_link = Regex::new(r#"\]\((?P<target>[^)\s]+)(\s+"[^"]*")?\)"#).unwrap();
+    let reference_link = Regex::new(r"\[(?P<text>[^\]]*)\]\[(?P<id>[^\]]*)\]").unwrap();
+    let footnote = Regex::new(r"\[\^(?P<id>[^\]]+)\](?P<def>:)?").unwrap();
+    let reference_def = Regex::new(r"^\s{0,3}\[(?P<id>[^\]^][^\]]*)\]:\s*(?P<target>\S+)").unwrap();
+
+    let mut anchors = BTreeSet::new();
+    let mut auto_ids: BTreeMap<String, usize> = BTreeMap::new();
+    let mut definitions = BTreeSet::new();
+    let mut footnotes = BTreeSet::new();
+
+    // Links we can only check once we know all anchors and definitions
+    let mut anchor_links = vec![];
+    let mut reference_links = vec![];
+    let mut footnote_links = vec![];
+
+    let mut report = LinkReport::default();
+    let mut section = String::new();
+    let mut in_code_block = false;
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+            // Code blocks can have IDs, too: ```{#id .rust}
+            if let Some(caps) = explicit_id.captures(line) {
+                anchors.insert(caps.name("id").unwrap_or("").to_owned());
+            }
+            continue;
+        }
+        if in_code_block {
+            continue;
+        }
+
+        if let Some(caps) = heading.captures(line) {
+            let id = caps.name("attrs").and_then(|attrs| explicit_id.captures(attrs))
+                                       .and_then(|id| id.name("id"))
+                                       .map(str::to_owned);
+            let id = id.unwrap_or_else(|| {
+                let id = auto_identifier(caps.name("title").unwrap_or(""));
+                let count = auto_ids.entry(id.clone()).or_insert(0);
+                *count += 1;
+                if *count == 1 { id } else { format!("{}-{}", id, *count - 1) }
+            });
+
+            section = id.clone();
+            anchors.insert(id);
+            continue;
+        }
+
+        let line = inline_code.replace_all(line, "");
+
+        for caps in html_id.captures_iter(&line) {
+            anchors.insert(caps.name("id").unwrap_or("").to_owned());
+        }
+
+        if let Some(caps) = reference_def.captures(&line) {
+            definitions.insert(caps.name("id").unwrap_or("").to_ascii_lowercase());
+            check_target(caps.name("target").unwrap_or(""), &section, &mut report, &mut anchor_links);
+            continue;
+        }
+
+        for caps in footnote.captures_iter(&line) {
+            let id = caps.name("id").unwrap_or("").to_ascii_lowercase();
+            if caps.name("def").is_some() {
+                footnotes.insert(id);
+            } else {
+                footnote_links.push((section.clone(), id));
+            }
+        }
+
+        for caps in inline_link.captures_iter(&line) {
+            check_target(caps.name("target").unwrap_or(""), &section, &mut report, &mut anchor_links);
+        }
+
+        for caps in reference_link.captures_iter(&line) {
+            // `[text][]` uses the text as ID
+            let id = match caps.name("id").unwrap_or("") {
+                "" => caps.name("text").unwrap_or(""),
+                id => id,
+            };
+            reference_links.push((section.clone(), id.to_ascii_lowercase()));
+        }
+    }
+
+    for (section, target) in anchor_links {
+        if !anchors.contains(&target) {
+            report.broken.push(BrokenLink::MissingAnchor { section: section, target: target });
+        }
+    }
+
+    for (section, id) in reference_links {
+        if !definitions.contains(&id) {
+            report.broken.push(BrokenLink::MissingReference { section: section, id: id });
+        }
+    }
+
+    for (section, id) in footnote_links {
+        if !footnotes.contains(&id) {
+            report.broken.push(BrokenLink::MissingReference {
+                section: section,
+                id: format!("^{}", id),
+            });
+        }
+    }
+
+    report
+}
+
+#[test]
+fn find_broken_links() {
+    let book = r#"# Ownership {#sec--ownership}
+
+See [borrowing](#sec--borrowing), [the example](#examples), [the other
+example](#examples-1) and [the docs][ownership.md--docs].
+
+## Examples
+
+### `Box<T>`
+
+<span id="code-example"></span>
+
+```rust
+let x = [1][2];
+```
+
+```{#listing .rust}
+let y = 1;
+```
+
+Also see [this](#code-example), [that](#boxt), [that](#nope), [the listing](#listing) and `[not a link](#nope)`.
+
+# Lifetimes {#sec--lifetimes}
+
+[It's in here](lifetimes.md) and [here][missing], also see[^note].
+
+## Examples
+
+[ownership.md--docs]: http://doc.rust-lang.org/std
+"#;
+
+    let report = check_links(book);
+
+    assert_eq!(report.broken, vec![
+        BrokenLink::RelativeLink { section: "sec--lifetimes".into(), target: "lifetimes.md".into() },
+        BrokenLink::MissingAnchor { section: "sec--ownership".into(), target: "sec--borrowing".into() },
+        BrokenLink::MissingAnchor { section: "boxt".into(), target: "nope".into() },
+        BrokenLink::MissingReference { section: "sec--lifetimes".into(), id: "missing".into() },
+        BrokenLink::MissingReference { section: "sec--lifetimes".into(), id: "^note".into() },
+    ]);
+    assert_eq!(report.external.get("http://doc.rust-lang.org/std"), Some(&1));
+}
@@ -0,0 +1,261 @@
+//! Link terms to their definitions in the glossary
+//!
+//! The first use of a term in each chapter becomes a reference-style link
+//! `[term][glossary--id]` (like the `{prefix}--{id}` references of
+//! `adjust_reference_names`), and each definition lists the chapters that use
+//! the term.
+
+use regex::{self, Regex, Captures};
+
+use helpers::adjust_header_ids::auto_identifier;
+
+const CODE_BLOCK_TOGGLE: &'static str = "```";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlossaryEntry {
+    /// The term and other names for it, e.g. `DST` and `Dynamically Sized Type`
+    pub names: Vec<String>,
+    /// ID of the definition's heading
+    pub id: String,
+    /// Only set for glossaries from a YAML file, glossary chapters already
+    /// contain their definitions
+    pub definition: Option<String>,
+}
+
+/// Headings of the glossary
+pub struct Headings<'a> {
+    pub glossary: &'a str,
+    pub used_in: &'a str,
+}
+
+/// The names of a glossary heading like `DST (Dynamically Sized Type)`
+pub fn term_names(heading: &str) -> Vec<String> {
+    let alias = Regex::new(r"^(?P<term>.+?)\s*\((?P<alias>[^)]+)\)$").unwrap();
+    match alias.captures(heading.trim()) {
+        Some(caps) => vec![caps.name("term").unwrap().to_owned(), caps.name("alias").unwrap().to_owned()],
+        None => vec![heading.trim().to_owned()],
+    }
+}
+
+fn reference_id(entry: &GlossaryEntry) -> String {
+    format!("glossary--{}", auto_identifier(&entry.names[0]))
+}
+
+/// Link the first use of each term per chapter to its definition and list
+/// the chapters using it below the definition. Without a `glossary_chapter`
+/// (the ID of the chapter containing the definitions), a glossary with the
+/// entries' definitions is added to the end of the book.
+pub fn link_glossary(input: &str,
+                     entries: &[GlossaryEntry],
+                     glossary_chapter: Option<&str>,
+                     headings: &Headings)
+                     -> String {
+    if entries.is_empty() {
+        return input.to_owned();
+    }
+
+    let chapter_headline = Regex::new(r"^#{1,2}\s+(?P<title>.+?)\s*\{#(?P<id>[^\s}]+)[^}]*\}\s*$").unwrap();
+    let headline = Regex::new(r"^#+\s.*?(\{#(?P<id>[^\s}]+)[^}]*\})?\s*$").unwrap();
+    let reference_def = Regex::new(r"^\s{0,3}\[[^\]]+\]:").unwrap();
+    // Code, links and HTML tags stay as they are
+    let protected = Regex::new(r"`+[^`]+`+|!?\[[^\]]*\](\([^)]*\)|\[[^\]]*\])|<[^>]+>").unwrap();
+
+    let mut names: Vec<(&String, usize)> = entries.iter()
+        .enumerate()
+        .flat_map(|(index, entry)| entry.names.iter().map(move |name| (name, index)))
+        .collect();
+    names.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+    let pattern = names.iter().map(|&(name, _)| regex::quote(name)).collect::<Vec<_>>().join("|");
+    let term = Regex::new(&format!(r"(?i)\b({})\b", pattern)).unwrap();
+
+    // `None` if the case-insensitive match doesn't lowercase to a name again
+    let find_entry = |found: &str| -> Option<usize> {
+        names.iter()
+             .find(|&&(name, _)| name.to_lowercase() == found.to_lowercase())
+             .map(|&(_, index)| index)
+    };
+
+    // Chapters (ID and title) using each entry
+    let mut uses: Vec<Vec<(String, String)>> = vec![vec![]; entries.len()];
+    let mut chapter: Option<(String, String)> = None;
+    let mut linked = vec![false; entries.len()];
+
+    let mut output = String::with_capacity(input.len());
+    let mut in_code_block = false;
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+        if !in_code_block {
+            if let Some(caps) = chapter_headline.captures(line) {
+                chapter = Some((caps.name("id").unwrap().to_owned(), caps.name("title").unwrap().to_owned()));
+                linked = vec![false; entries.len()];
+            }
+        }
+
+        let in_glossary = chapter.as_ref().map(|c| &c.0[..]) == glossary_chapter;
+        if in_code_block || chapter.is_none() || in_glossary || headline.is_match(line) ||
+           reference_def.is_match(line) {
+            output.push_str(line);
+            output.push_str("\n");
+            continue;
+        }
+
+        let mut link = |text: &str| -> String {
+            term.replace_all(text, |caps: &Captures| {
+                let found = caps.at(0).unwrap();
+                let index = match find_entry(found) {
+                    Some(index) if !linked[index] => index,
+                    _ => return found.to_owned(),
+                };
+                linked[index] = true;
+                uses[index].push(chapter.clone().unwrap());
+                format!("[{}][{}]", found, reference_id(&entries[index]))
+            })
+        };
+
+        let mut last = 0;
+        for (start, end) in protected.find_iter(line) {
+            output.push_str(&link(&line[last..start]));
+            output.push_str(&line[start..end]);
+            last = end;
+        }
+        output.push_str(&link(&line[last..]));
+        output.push_str("\n");
+    }
+
+    let back_references = |index: usize| -> String {
+        if uses[index].is_empty() {
+            return String::new();
+        }
+        let links: Vec<String> = uses[index].iter()
+                                            .map(|&(ref id, ref title)| format!("[{}](#{})", title, id))
+                                            .collect();
+        format!("{}: {}\n\n", headings.used_in, links.join(", "))
+    };
+
+    let output = match glossary_chapter {
+        Some(glossary_chapter) => {
+            // Add the back references before the next heading after each definition
+            let mut with_uses = String::with_capacity(output.len());
+            let mut current: Option<usize> = None;
+            let mut in_glossary = false;
+            let mut in_code_block = false;
+
+            for line in output.lines() {
+                if line.starts_with(CODE_BLOCK_TOGGLE) {
+                    in_code_block = !in_code_block;
+                }
+                if let (false, Some(caps)) = (in_code_block, headline.captures(line)) {
+                    if let Some(index) = current.take() {
+                        with_uses.push_str(&back_references(index));
+                    }
+                    if let Some(caps) = chapter_headline.captures(line) {
+                        in_glossary = caps.name("id") == Some(glossary_chapter);
+                    }
+                    if in_glossary {
+                        let id = caps.name("id").unwrap_or("");
+                        current = entries.iter().position(|entry| entry.id == id);
+                    }
+                }
+                with_uses.push_str(line);
+                with_uses.push_str("\n");
+            }
+            if let Some(index) = current {
+                with_uses.push_str("\n");
+                with_uses.push_str(&back_references(index));
+            }
+            with_uses
+        }
+        None => {
+            let mut with_glossary = output;
+            with_glossary.push_str(&format!("\n# {} {{#glossary .unnumbered}}\n", headings.glossary));
+            for (index, entry) in entries.iter().enumerate() {
+                let title = match entry.names.split_first() {
+                    Some((term, aliases)) if !aliases.is_empty() => {
+                        format!("{} ({})", term, aliases.join(", "))
+                    }
+                    _ => entry.names.join(""),
+                };
+                with_glossary.push_str(&format!("\n### {} {{#{}}}\n\n{}\n\n",
+                                                title,
+                                                entry.id,
+                                                entry.definition.as_ref().map_or("", |d| &d[..])));
+                with_glossary.push_str(&back_references(index));
+            }
+            with_glossary
+        }
+    };
+
+    let mut output = output;
+    output.push_str("\n");
+    for entry in entries {
+        output.push_str(&format!("[{}]: #{}\n", reference_id(entry), entry.id));
+    }
+
+    output
+}
+
+#[test]
+fn glossary_links() {
+    let book = "# Ownership {#sec--ownership}
+
+An expression, not a statement. Another expression and a `statement`.
+
+# Glossary {#sec--glossary}
+
+### Expression {#sec--glossary--expression}
+
+Something with a value.
+
+### DST (Dynamically Sized Type) {#sec--glossary--dst-dynamically-sized-type}
+
+A type without a known size.
+
+# Traits {#sec--traits}
+
+Trait objects are [a DST](#sec--glossary) and a dynamically sized type.
+";
+
+    let entries = vec![
+        GlossaryEntry {
+            names: term_names("Expression"),
+            id: "sec--glossary--expression".into(),
+            definition: None,
+        },
+        GlossaryEntry {
+            names: term_names("DST (Dynamically Sized Type)"),
+            id: "sec--glossary--dst-dynamically-sized-type".into(),
+            definition: None,
+        },
+    ];
+    let headings = Headings { glossary: "Glossary", used_in: "Used in" };
+
+    assert_eq!(link_glossary(book, &entries, Some("sec--glossary"), &headings),
+               "# Ownership {#sec--ownership}
+
+An [expression][glossary--expression], not a statement. Another expression and a `statement`.
+
+# Glossary {#sec--glossary}
+
+### Expression {#sec--glossary--expression}
+
+Something with a value.
+
+Used in: [Ownership](#sec--ownership)
+
+### DST (Dynamically Sized Type) {#sec--glossary--dst-dynamically-sized-type}
+
+A type without a known size.
+
+Used in: [Traits](#sec--traits)
+
+# Traits {#sec--traits}
+
+Trait objects are [a DST](#sec--glossary) and a [dynamically sized type][glossary--dst].
+
+[glossary--expression]: #sec--glossary--expression
+[glossary--dst]: #sec--glossary--dst-dynamically-sized-type
+");
+}
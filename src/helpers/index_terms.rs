@@ -0,0 +1,259 @@
+//! Back-of-book index
+//!
+//! Index terms are the configured keywords (in prose and code spans) and code
+//! spans that name a std item, e.g. `Vec<T>` or `std::rc::Rc`. The PDF gets
+//! `\index{}` entries for makeindex, the EPUB an index page linking to the
+//! first use of a term in each chapter.
+
+use std::collections::BTreeMap;
+use regex::{self, Regex, Captures};
+
+const CODE_BLOCK_TOGGLE: &'static str = "```";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexFormat {
+    Latex,
+    Html,
+}
+
+/// The index term a code span names, if any
+fn code_term(code: &str, keywords: &[&String], std_items: &[&str]) -> Option<String> {
+    let code = code.trim_matches('`').trim();
+    if keywords.iter().any(|k| *k == code) {
+        return Some(code.to_owned());
+    }
+
+    // `std::collections::HashMap<K, V>` names `HashMap`, `Vec::new()` names `Vec`
+    let path = code.split('<').next().unwrap_or(code).trim_right_matches("()");
+    path.split("::")
+        .filter(|segment| std_items.contains(segment))
+        .last()
+        .map(str::to_owned)
+}
+
+/// Entry for `\index{}`, quoting makeindex's special characters
+fn latex_entry(term: &str, code: bool) -> String {
+    let quote = |s: &str| -> String {
+        s.chars().fold(String::new(), |mut out, c| {
+            if "!@|\"".contains(c) {
+                out.push('"');
+            }
+            out.push(c);
+            out
+        })
+    };
+    let display = term.replace("\\", "\\textbackslash{}")
+                      .replace("_", "\\_")
+                      .replace("&", "\\&")
+                      .replace("#", "\\#")
+                      .replace("%", "\\%")
+                      .replace("$", "\\$");
+
+    if code {
+        format!("\\index{{{}@\\texttt{{{}}}}}", quote(term), quote(&display))
+    } else if display != term {
+        format!("\\index{{{}@{}}}", quote(term), quote(&display))
+    } else {
+        format!("\\index{{{}}}", quote(term))
+    }
+}
+
+/// Uses of terms for the EPUB index page
+struct Entries {
+    /// Titles of the chapters so far
+    chapters: Vec<String>,
+    /// Term -> chapter number and anchor of the first use in that chapter
+    uses: BTreeMap<String, Vec<(usize, String)>>,
+    count: usize,
+}
+
+impl Entries {
+    fn add(&mut self, term: &str) -> String {
+        if self.chapters.is_empty() {
+            return String::new();
+        }
+        let chapter = self.chapters.len() - 1;
+        let uses = self.uses.entry(term.to_owned()).or_insert_with(Vec::new);
+        if uses.last().map_or(false, |&(c, _)| c == chapter) {
+            return String::new();
+        }
+
+        self.count += 1;
+        let id = format!("index--{}", self.count);
+        uses.push((chapter, id.clone()));
+        format!("<span id=\"{}\"></span>", id)
+    }
+
+    fn render(&self, title: &str) -> String {
+        let mut terms: Vec<(&String, &Vec<(usize, String)>)> = self.uses.iter().collect();
+        terms.sort_by_key(|&(term, _)| term.trim_matches('`').to_lowercase());
+
+        let mut output = format!("\n# {} {{#index .unnumbered}}\n\n", title);
+        for (term, uses) in terms {
+            let links: Vec<String> = uses.iter()
+                                         .map(|&(chapter, ref id)| {
+                                             format!("[{}](#{})", self.chapters[chapter], id)
+                                         })
+                                         .collect();
+            output.push_str(&format!("- {}: {}\n", term, links.join(", ")));
+        }
+        output
+    }
+}
+
+/// Mark the index terms in `input` for the given format. For HTML, this adds
+/// an index page called `title` to the end of the book.
+pub fn add_index(input: &str,
+                 keywords: &[String],
+                 std_items: &[&str],
+                 format: IndexFormat,
+                 title: &str)
+                 -> String {
+    let chapter_headline = Regex::new(r"^#{1,2}\s+(?P<title>.+?)\s*\{#[^}]*\}\s*$").unwrap();
+    let headline = Regex::new(r"^#+\s").unwrap();
+    let reference_def = Regex::new(r"^\s{0,3}\[[^\]]+\]:").unwrap();
+    // Code spans are checked for items; link targets, HTML and attributes are left alone
+    let protected = Regex::new(r"`+[^`]+`+|\]\([^)]*\)|<[^>]+>|\{[^}]*\}|\\[a-zA-Z]+")
+                        .unwrap();
+
+    let mut keywords: Vec<&String> = keywords.iter().collect();
+    keywords.sort_by(|a, b| b.len().cmp(&a.len()));
+    let keyword_pattern = keywords.iter().map(|k| regex::quote(k)).collect::<Vec<_>>().join("|");
+    let keyword = if keywords.is_empty() {
+        None
+    } else {
+        Some(Regex::new(&format!(r"(?i)\b({})\b", keyword_pattern)).unwrap())
+    };
+
+    let find_keyword = |text: &str| -> Option<String> {
+        keywords.iter()
+                .find(|k| k.to_lowercase() == text.to_lowercase())
+                .map(|k| (*k).clone())
+    };
+
+    let mut entries = Entries { chapters: vec![], uses: BTreeMap::new(), count: 0 };
+    let mut output = String::with_capacity(input.len());
+    let mut in_code_block = false;
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+        if in_code_block || reference_def.is_match(line) {
+            output.push_str(line);
+            output.push_str("\n");
+            continue;
+        }
+        if headline.is_match(line) {
+            if let Some(caps) = chapter_headline.captures(line) {
+                entries.chapters.push(caps.name("title").unwrap().to_owned());
+            }
+            output.push_str(line);
+            output.push_str("\n");
+            continue;
+        }
+        // The meta data blocks before the first chapter are not part of the text
+        if entries.chapters.is_empty() {
+            output.push_str(line);
+            output.push_str("\n");
+            continue;
+        }
+
+        let mut mark = |term: &str, code: bool, text: &str| -> String {
+            match format {
+                IndexFormat::Latex => format!("{}{}", text, latex_entry(term, code)),
+                IndexFormat::Html if code => format!("{}{}", entries.add(&format!("`{}`", term)), text),
+                IndexFormat::Html => format!("{}{}", entries.add(term), text),
+            }
+        };
+
+        // Split the line at the protected parts, the `None` at the end marks
+        // the text after the last one
+        let mut last = 0;
+        let tokens = protected.find_iter(line).map(Some).chain(Some(None));
+
+        for token in tokens {
+            let (start, end) = token.unwrap_or((line.len(), line.len()));
+            let text = &line[last..start];
+
+            match keyword {
+                Some(ref keyword) => {
+                    output.push_str(&keyword.replace_all(text, |caps: &Captures| {
+                        let found = caps.at(0).unwrap();
+                        let term = find_keyword(found).unwrap_or(found.to_owned());
+                        mark(&term, false, found)
+                    }))
+                }
+                None => output.push_str(text),
+            }
+
+            let token = &line[start..end];
+            match code_term(token, &keywords, std_items) {
+                Some(ref term) if token.starts_with('`') => output.push_str(&mark(term, true, token)),
+                _ => output.push_str(token),
+            }
+            last = end;
+        }
+
+        output.push_str("\n");
+    }
+
+    if format == IndexFormat::Html && !entries.uses.is_empty() {
+        output.push_str(&entries.render(title));
+    }
+
+    output
+}
+
+#[test]
+fn index_entries() {
+    let book = "# Ownership {#sec--ownership}
+
+Ownership and `Vec<T>` in [ownership](#sec--ownership), not `vec![]`.
+
+```rust
+let v: Vec<i32> = vec![];
+```
+
+# Traits {#sec--traits}
+
+`impl Trait` and `std::rc::Rc::new()`, e.g. for `a_b`.
+";
+
+    let keywords = vec!["ownership".to_owned(), "impl Trait".to_owned()];
+    let std_items = ["Rc", "Vec"];
+
+    let latex = add_index(book, &keywords, &std_items, IndexFormat::Latex, "Index");
+    assert!(latex.contains(r"Ownership\index{ownership} and `Vec<T>`\index{Vec@\texttt{Vec}}"));
+    assert!(latex.contains(r"[ownership\index{ownership}](#sec--ownership), not `vec![]`."));
+    assert!(latex.contains(r"`impl Trait`\index{impl Trait@\texttt{impl Trait}} and"));
+    assert!(latex.contains(r"`std::rc::Rc::new()`\index{Rc@\texttt{Rc}}, e.g. for `a_b`."));
+    assert!(latex.contains("let v: Vec<i32> = vec![];\n"));
+
+    let html = add_index(book, &keywords, &std_items, IndexFormat::Html, "Index");
+    assert!(html.ends_with("# Index {#index .unnumbered}
+
+- `impl Trait`: [Traits](#index--3)
+- ownership: [Ownership](#index--1)
+- `Rc`: [Traits](#index--4)
+- `Vec`: [Ownership](#index--2)
+"));
+}
+
+#[test]
+fn no_index_entries_in_meta_data() {
+    let book = "---
+title: Ownership in Rust
+index-terms:
+  - ownership
+...
+
+# Ownership {#sec--ownership}
+
+About ownership.
+";
+
+    let latex = add_index(book, &["ownership".to_owned()], &[], IndexFormat::Latex, "Index");
+    assert!(latex.starts_with("---\ntitle: Ownership in Rust\nindex-terms:\n  - ownership\n...\n"));
+    assert!(latex.contains("About ownership\\index{ownership}."));
+}
@@ -1,19 +1,141 @@
 use std::error::Error;
 
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Characters after which we may break a line (when followed by something that
+/// is not punctuation itself, so we don't split tokens like `::` or `->`).
+const BREAK_AFTER: &'static str = ",;:.([{)]}+-*/=&|<>";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Break {
+    Whitespace,
+    Punctuation,
+}
+
+/// A grapheme cluster and the number of columns it occupies in a monospace font
+type Grapheme<'a> = (&'a str, usize);
+
+fn is_punctuation(grapheme: &str) -> bool {
+    grapheme.chars().next().map_or(false, |c| BREAK_AFTER.contains(c))
+}
+
+fn break_after(current: Grapheme, next: Option<Grapheme>) -> Option<Break> {
+    let next_is_punctuation = next.map_or(false, |(n, _)| is_punctuation(n));
+
+    if current.0.chars().all(char::is_whitespace) {
+        Some(Break::Whitespace)
+    } else if is_punctuation(current.0) && !next_is_punctuation {
+        Some(Break::Punctuation)
+    } else if current.1 > 1 && next.map_or(false, |(_, w)| w > 1) {
+        // Wide (CJK) text contains no spaces but may be broken anywhere
+        Some(Break::Punctuation)
+    } else {
+        None
+    }
+}
+
+fn display_width(graphemes: &[Grapheme]) -> usize {
+    graphemes.iter().map(|&(_, width)| width).sum()
+}
+
+/// Find the number of graphemes to put on a line with `width` columns
+/// available.
+///
+/// Prefers breaking after whitespace in the last third of the line, then
+/// after punctuation in the second half, and only splits a token if there is
+/// no other way. Always returns at least 1, so we make progress even if a
+/// single grapheme is wider than the line.
+fn find_break(graphemes: &[Grapheme], width: usize) -> usize {
+    if display_width(graphemes) <= width {
+        return graphemes.len();
+    }
+
+    let mut last_whitespace = None;
+    let mut last_punctuation = None;
+    let mut fits = 0;
+    let mut line_width = 0;
+
+    for (index, &grapheme) in graphemes.iter().enumerate() {
+        line_width += grapheme.1;
+        if line_width > width {
+            break;
+        }
+        fits = index + 1;
+
+        // Breaking positions are measured in columns, too
+        match break_after(grapheme, graphemes.get(index + 1).cloned()) {
+            Some(Break::Whitespace) => last_whitespace = Some((index + 1, line_width)),
+            Some(Break::Punctuation) => last_punctuation = Some((index + 1, line_width)),
+            None => {}
+        }
+    }
+
+    match (last_whitespace, last_punctuation) {
+        (Some((ws, ws_width)), _) if ws_width * 3 >= width * 2 => ws,
+        (ws, punct) => {
+            match ::std::cmp::max(ws, punct) {
+                Some((pos, pos_width)) if pos_width * 2 >= width => pos,
+                _ => ::std::cmp::max(fits, 1),
+            }
+        }
+    }
+}
+
+/// Break `line` into lines of at most `max_len` columns.
+///
+/// Widths are measured in monospace columns: East Asian wide characters and
+/// most emoji take two columns, combining marks none. Grapheme clusters are
+/// never split. Continuation lines keep the indentation of the original line
+/// and are prefixed with `sep`. Whitespace at the end of a broken line is
+/// dropped.
 pub fn break_long_line(line: &str, max_len: usize, sep: &str) -> Result<String, Box<Error>> {
-    let sep_length = sep.chars().count() as usize;
-    let mut output = String::with_capacity(line.len());
+    let graphemes: Vec<Grapheme> = UnicodeSegmentation::graphemes(line, true)
+        .map(|g| (g, UnicodeWidthStr::width(g)))
+        .collect();
+
+    if display_width(&graphemes) <= max_len {
+        return Ok(line.to_owned());
+    }
+
+    let indent: String = line.chars().take_while(|c| c.is_whitespace()).collect();
+    let prefix = format!("{}{}", indent, sep);
+    let prefix_width = UnicodeWidthStr::width(&prefix[..]);
+
+    // Make sure each line gets at least a few columns of content
+    let continuation_width = if max_len > prefix_width + 10 {
+        max_len - prefix_width
+    } else {
+        10
+    };
+
+    let mut output = String::with_capacity(line.len() + line.len() / max_len * prefix.len());
+    let mut rest = &graphemes[..];
+    let mut width = max_len;
 
-    // First time: `max_len`, after that `max_len - sep_length`
-    let mut line_end = max_len;
+    loop {
+        let end = find_break(rest, width);
+        let (current, next) = rest.split_at(end);
+        rest = next;
 
-    for (index, ch) in line.chars().enumerate() {
-        if index >= (line_end - 1) {
-            line_end += max_len - sep_length - 1;
-            output.push_str("\n");
-            output.push_str(sep);
+        // The line break replaces the whitespace we broke at
+        let content_end = if rest.is_empty() {
+            current.len()
+        } else {
+            current.iter().rposition(|&(g, _)| !g.trim().is_empty()).map_or(0, |i| i + 1)
+        };
+
+        for &(grapheme, _) in &current[..content_end] {
+            output.push_str(grapheme);
+        }
+
+        if rest.is_empty() {
+            break;
         }
-        output.push(ch);
+
+        output.push_str("\n");
+        output.push_str(&prefix);
+        width = continuation_width;
     }
 
     Ok(output)
@@ -23,10 +145,10 @@ pub fn break_long_line(line: &str, max_len: usize, sep: &str) -> Result<String,
 fn break_long_lines() {
     let long_line = "markdown+grid_tables+pipe_tables+raw_html+implicit_figures+footnotes+intraword_underscores+auto_identifiers-inline_code_attributesmarkdown+grid_tables+pipe_tables+raw_html+implicit_figures+footnotes+intraword_underscores+auto_identifiers-inline_code_attributes";
 
-    let correct_split = "markdown+grid_tables+pipe_tables+raw_html+implicit_figures+footnotes+intraword_
-↳ underscores+auto_identifiers-inline_code_attributesmarkdown+grid_tables+pipe_
-↳ tables+raw_html+implicit_figures+footnotes+intraword_underscores+auto_identif
-↳ iers-inline_code_attributes";
+    let correct_split = "markdown+grid_tables+pipe_tables+raw_html+implicit_figures+footnotes+
+↳ intraword_underscores+auto_identifiers-inline_code_attributesmarkdown+
+↳ grid_tables+pipe_tables+raw_html+implicit_figures+footnotes+
+↳ intraword_underscores+auto_identifiers-inline_code_attributes";
 
     let max_len = 80;
 
@@ -36,3 +158,35 @@ fn break_long_lines() {
     assert!(broken.lines().all(|x| x.chars().count() <= max_len));
     assert_eq!(broken.lines().count(), 4);
 }
+
+#[test]
+fn break_indented_code() {
+    let code = "        let message = format!(\"{}: {}\", some_long_variable_name, another_long_variable_name);";
+
+    let broken = "        let message = format!(\"{}: {}\",
+        ↳ some_long_variable_name,
+        ↳ another_long_variable_name);";
+
+    assert_eq!(break_long_line(code, 60, "↳ ").unwrap(), broken);
+}
+
+#[test]
+fn break_wide_characters() {
+    // Each of these takes up two columns
+    let japanese = "let greeting = \"こんにちは、世界。こんにちは、世界。\";";
+
+    let broken = break_long_line(japanese, 30, "↳ ").unwrap();
+
+    assert_eq!(broken, "let greeting = \"こんにちは、世\n↳ 界。こんにちは、世界。\";");
+    assert!(broken.lines().all(|x| UnicodeWidthStr::width(x) <= 30));
+}
+
+#[test]
+fn keep_graphemes_together() {
+    // `e` followed by a combining acute accent takes up one column
+    let accents = "e\u{301}e\u{301}e\u{301}e\u{301}e\u{301}e\u{301}";
+
+    assert_eq!(break_long_line(accents, 6, "").unwrap(), accents);
+    assert_eq!(break_long_line(accents, 4, "").unwrap(),
+               "e\u{301}e\u{301}e\u{301}e\u{301}\ne\u{301}e\u{301}");
+}
@@ -0,0 +1,214 @@
+//! Make external links usable on offline e-readers
+//!
+//! Every external link gets a number referring to an entry in a list of URLs
+//! at the end of its chapter (or the book). Entries link back to where the
+//! URL was used. Repeated URLs share one entry.
+
+use std::error::Error;
+use std::collections::BTreeMap;
+use std::ascii::AsciiExt;
+use regex::{Regex, Captures};
+
+const CODE_BLOCK_TOGGLE: &'static str = "```";
+
+/// Where to put the list of links
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkNotes {
+    Chapter,
+    Book,
+}
+
+impl LinkNotes {
+    pub fn from_str(name: &str) -> Option<LinkNotes> {
+        match name {
+            "chapter" => Some(LinkNotes::Chapter),
+            "book" => Some(LinkNotes::Book),
+            _ => None,
+        }
+    }
+}
+
+/// The links of one chapter (or the whole book)
+struct Notes {
+    id: String,
+    /// URLs in order of first use and the IDs of the places they are used at
+    urls: Vec<(String, Vec<String>)>,
+}
+
+impl Notes {
+    fn new(id: &str) -> Notes {
+        Notes { id: id.to_owned(), urls: vec![] }
+    }
+
+    /// Add a use of `url`, returns the markup to put after the link
+    fn add(&mut self, url: &str) -> String {
+        let index = match self.urls.iter().position(|&(ref u, _)| u == url) {
+            Some(index) => index,
+            None => {
+                self.urls.push((url.to_owned(), vec![]));
+                self.urls.len() - 1
+            }
+        };
+
+        let number = index + 1;
+        let ref_id = format!("{}-{}-ref{}", self.id, number, self.urls[index].1.len() + 1);
+        self.urls[index].1.push(ref_id.clone());
+
+        format!("<sup id=\"{ref_id}\">[[{number}]](#{id}-{number})</sup>",
+                ref_id = ref_id,
+                number = number,
+                id = self.id)
+    }
+
+    fn render(&self, level: usize, title: &str) -> String {
+        if self.urls.is_empty() {
+            return String::new();
+        }
+
+        let mut output = format!("\n{hashes} {title} {{#{id} .unnumbered .link-notes}}\n\n",
+                                 hashes = "#".repeat(level),
+                                 title = title,
+                                 id = self.id);
+
+        for (index, &(ref url, ref refs)) in self.urls.iter().enumerate() {
+            let back_refs: Vec<String> = refs.iter()
+                                             .map(|ref_id| format!("[↩](#{})", ref_id))
+                                             .collect();
+            output.push_str(&format!("{number}. <span id=\"{id}-{number}\"></span><{url}> {back_refs}\n",
+                                     number = index + 1,
+                                     id = self.id,
+                                     url = url,
+                                     back_refs = back_refs.join(" ")));
+        }
+
+        output.push_str("\n");
+        output
+    }
+}
+
+fn is_external(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://") || url.starts_with("ftp://")
+}
+
+/// Number all external links in `input` and collect them in lists called
+/// `title` at the end of each chapter or the book.
+pub fn collect_link_notes(input: &str, placement: LinkNotes, title: &str)
+    -> Result<String, Box<Error>>
+{
+    let chapter_headline = Regex::new(r"^#{1,2}\s.*\{#(?P<id>[^\s}]+)[^}]*\}\s*$").unwrap();
+    let headline = Regex::new(r"^#+\s").unwrap();
+    let inline_link = Regex::new(r#"\]\((?P<url>[^)\s]+)(\s+"[^"]*")?\)"#).unwrap();
+    let reference_link = Regex::new(r"(\[(?P<text>[^\]\[]*))?\]\[(?P<id>([^\]^][^\]]*)?)\]").unwrap();
+    let reference_def = Regex::new(r"^\s{0,3}\[(?P<id>[^\]^][^\]]*)\]:\s*(?P<url>\S+)").unwrap();
+
+    // Reference-style links may be defined after they are used
+    let mut references = BTreeMap::new();
+    let mut in_code_block = false;
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        } else if let Some(caps) = reference_def.captures(line) {
+            let url = caps.name("url").unwrap();
+            if !in_code_block && is_external(url) {
+                references.insert(caps.name("id").unwrap().to_ascii_lowercase(), url.to_owned());
+            }
+        }
+    }
+
+    let mut output = String::with_capacity(input.len());
+    let mut notes = Notes::new("links");
+    let mut in_code_block = false;
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+        if in_code_block {
+            output.push_str(line);
+            output.push_str("\n");
+            continue;
+        }
+
+        if headline.is_match(line) {
+            if let (LinkNotes::Chapter, Some(caps)) = (placement, chapter_headline.captures(line)) {
+                output.push_str(&notes.render(3, title));
+                notes = Notes::new(&format!("links--{}", caps.name("id").unwrap()));
+            }
+            output.push_str(line);
+            output.push_str("\n");
+            continue;
+        }
+
+        let line = inline_link.replace_all(line, |caps: &Captures| {
+            let url = caps.name("url").unwrap();
+            if is_external(url) {
+                format!("{}{}", caps.at(0).unwrap(), notes.add(url))
+            } else {
+                caps.at(0).unwrap().to_owned()
+            }
+        });
+
+        let line = reference_link.replace_all(&line, |caps: &Captures| {
+            // `[text][]` uses the text as ID
+            let id = match caps.name("id").unwrap_or("") {
+                "" => caps.name("text").unwrap_or(""),
+                id => id,
+            };
+
+            match references.get(&id.to_ascii_lowercase()) {
+                Some(url) => format!("{}{}", caps.at(0).unwrap(), notes.add(url)),
+                None => caps.at(0).unwrap().to_owned(),
+            }
+        });
+
+        output.push_str(&line);
+        output.push_str("\n");
+    }
+
+    output.push_str(&notes.render(if placement == LinkNotes::Book { 1 } else { 3 }, title));
+
+    Ok(output)
+}
+
+#[test]
+fn chapter_link_notes() {
+    let book = "# Ownership {#sec--ownership}
+
+See [the docs](https://doc.rust-lang.org/std), [the
+forum][forum], and [the docs](https://doc.rust-lang.org/std) again.
+
+```rust
+let x = [1](2);
+```
+
+[forum]: https://users.rust-lang.org
+
+# Lifetimes {#sec--lifetimes}
+
+No links [here](#sec--ownership).
+";
+
+    let expected = "# Ownership {#sec--ownership}
+
+See [the docs](https://doc.rust-lang.org/std)<sup id=\"links--sec--ownership-1-ref1\">[[1]](#links--sec--ownership-1)</sup>, [the
+forum][forum]<sup id=\"links--sec--ownership-2-ref1\">[[2]](#links--sec--ownership-2)</sup>, and [the docs](https://doc.rust-lang.org/std)<sup id=\"links--sec--ownership-1-ref2\">[[1]](#links--sec--ownership-1)</sup> again.
+
+```rust
+let x = [1](2);
+```
+
+[forum]: https://users.rust-lang.org
+
+
+### Links {#links--sec--ownership .unnumbered .link-notes}
+
+1. <span id=\"links--sec--ownership-1\"></span><https://doc.rust-lang.org/std> [↩](#links--sec--ownership-1-ref1) [↩](#links--sec--ownership-1-ref2)
+2. <span id=\"links--sec--ownership-2\"></span><https://users.rust-lang.org> [↩](#links--sec--ownership-2-ref1)
+
+# Lifetimes {#sec--lifetimes}
+
+No links [here](#sec--ownership).
+";
+
+    assert_eq!(collect_link_notes(book, LinkNotes::Chapter, "Links").unwrap(), expected);
+}
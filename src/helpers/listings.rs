@@ -0,0 +1,223 @@
+//! Number the code listings of a chapter, e.g. "Listing 4-2"
+//!
+//! Rust code blocks (and other blocks with a caption or anchor) get a caption
+//! line. Captions come from a `<span class="caption">…</span>` line before the
+//! block or a `caption="…"` fence attribute, anchors from an empty
+//! `<span id="…"></span>` line before it. Once all chapters are numbered,
+//! `link_listings` completes the links to a listing with its number.
+
+use std::collections::BTreeMap;
+use regex::{Regex, Captures};
+
+use helpers::adjust_header_ids::section_id;
+
+const CODE_BLOCK_TOGGLE: &'static str = "```";
+
+/// Numbering of the listings in a chapter
+pub struct Numbering<'a> {
+    /// Number of the chapter (the first part of "4-2")
+    pub chapter: usize,
+    /// Number of listings in this chapter so far
+    pub count: usize,
+    /// What a listing is called, e.g. "Listing"
+    pub label: &'a str,
+    /// Listing ID and number for the link targets of the listings numbered so
+    /// far, which are their ID and (for links from other chapters) its
+    /// `section_id`
+    pub numbers: BTreeMap<String, (String, String)>,
+}
+
+fn is_rust(info: &str) -> bool {
+    info.is_empty() || info.split(|c| c == ',' || c == ' ').any(|class| class == "rust")
+}
+
+/// Add numbers and captions to the listings in `input` (the chapter with the
+/// given file ID), continuing the numbering's count.
+pub fn number_listings(input: &str, file_id: &str, numbering: &mut Numbering) -> String {
+    let anchor = Regex::new(r#"^\s*<span id="(?P<id>[^"]+)">\s*</span>\s*$"#).unwrap();
+    let caption = Regex::new(r#"^\s*<span class="caption">(?P<caption>.*)</span>\s*$"#).unwrap();
+    let fence_caption = Regex::new(r#"\s*caption="(?P<caption>[^"]*)""#).unwrap();
+
+    // Anchor and caption lines we have seen since the last paragraph
+    let mut pending_id: Option<String> = None;
+    let mut pending_caption: Option<String> = None;
+    let mut lines = vec![];
+    let mut in_code_block = false;
+
+    for line in input.lines() {
+        if in_code_block {
+            if line.starts_with(CODE_BLOCK_TOGGLE) {
+                in_code_block = false;
+            }
+            lines.push(line.to_owned());
+            continue;
+        }
+
+        if let Some(caps) = anchor.captures(line) {
+            pending_id = Some(caps.name("id").unwrap().to_owned());
+            continue;
+        }
+        if let Some(caps) = caption.captures(line) {
+            pending_caption = Some(caps.name("caption").unwrap().trim().to_owned());
+            continue;
+        }
+
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = true;
+            let info = line[CODE_BLOCK_TOGGLE.len()..].trim();
+            let caption = fence_caption.captures(info)
+                                       .map(|caps| caps.name("caption").unwrap().to_owned())
+                                       .or(pending_caption.take());
+            let info = fence_caption.replace_all(info, "");
+
+            if is_rust(&info) || caption.is_some() || pending_id.is_some() {
+                numbering.count += 1;
+                let number = format!("{}-{}", numbering.chapter, numbering.count);
+                let id = pending_id.take()
+                                   .unwrap_or_else(|| format!("listing-{}", number));
+                numbering.numbers.insert(section_id(file_id, &id), (id.clone(), number.clone()));
+                numbering.numbers.insert(id.clone(), (id.clone(), number.clone()));
+
+                // Blank lines between anchor, caption and code block
+                while lines.last().map_or(false, |l: &String| l.is_empty()) {
+                    lines.pop();
+                }
+                if !lines.is_empty() {
+                    lines.push(String::new());
+                }
+
+                lines.push(format!("**{label} {number}**{caption}",
+                                   label = numbering.label,
+                                   number = number,
+                                   caption = caption.map_or(String::new(), |c| format!(": {}", c))));
+                lines.push(String::new());
+                // Becomes the code block's ID in `anchors_to_identifiers`
+                lines.push(format!("<span id=\"{}\"></span>", id));
+                lines.push(String::new());
+            }
+
+            lines.push(format!("{}{}", CODE_BLOCK_TOGGLE, info));
+            continue;
+        }
+
+        // Anchors not followed by a code block stay as they are
+        if !line.trim().is_empty() {
+            if let Some(id) = pending_id.take() {
+                lines.push(format!("<span id=\"{}\"></span>", id));
+                lines.push(String::new());
+            }
+            if let Some(caption) = pending_caption.take() {
+                lines.push(format!("<span class=\"caption\">{}</span>", caption));
+                lines.push(String::new());
+            }
+        }
+        lines.push(line.to_owned());
+    }
+
+    let mut output = lines.join("\n");
+    output.push_str("\n");
+    output
+}
+
+/// Complete the links to numbered listings anywhere in the book with their
+/// number.
+pub fn link_listings(input: &str, numbering: &Numbering) -> String {
+    let listing_link = Regex::new(r"\[(?P<text>[^\]]*)\]\(#(?P<id>[^)\s]+)\)").unwrap();
+
+    let mut output = String::with_capacity(input.len());
+    let mut in_code_block = false;
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+        if in_code_block {
+            output.push_str(line);
+        } else {
+            output.push_str(&listing_link.replace_all(line, |caps: &Captures| {
+                let text = caps.name("text").unwrap();
+                match numbering.numbers.get(caps.name("id").unwrap()) {
+                    Some(&(ref id, ref number)) if text.is_empty() => {
+                        format!("[{} {}](#{})", numbering.label, number, id)
+                    }
+                    Some(&(ref id, ref number)) => {
+                        format!("[{}](#{}) ({} {})", text, id, numbering.label, number)
+                    }
+                    None => caps.at(0).unwrap().to_owned(),
+                }
+            }));
+        }
+        output.push_str("\n");
+    }
+
+    output
+}
+
+#[test]
+fn listing_numbers() {
+    let chapter = r#"As in [the example](#code-double) and [](#code-double):
+
+```text
+$ cargo run
+```
+
+<span id="code-double"></span>
+
+```rust,should_panic
+fn main() {}
+```
+
+<span class="caption">A struct</span>
+
+```rust
+struct Point;
+```
+
+```sh caption="Running it"
+$ ./main
+```
+"#;
+
+    let mut numbering = Numbering { chapter: 4, count: 1, label: "Listing", numbers: BTreeMap::new() };
+    let numbered = number_listings(chapter, "ch04-00", &mut numbering);
+    numbering.chapter = 5;
+    numbering.count = 0;
+    let next_chapter = number_listings("See [the struct](#sec--ch04-00--listing-4-3).\n\n```rust\n```\n",
+                                       "ch05-00",
+                                       &mut numbering);
+
+    assert_eq!(link_listings(&numbered, &numbering), r#"As in [the example](#code-double) (Listing 4-2) and [Listing 4-2](#code-double):
+
+```text
+$ cargo run
+```
+
+**Listing 4-2**
+
+<span id="code-double"></span>
+
+```rust,should_panic
+fn main() {}
+```
+
+**Listing 4-3**: A struct
+
+<span id="listing-4-3"></span>
+
+```rust
+struct Point;
+```
+
+**Listing 4-4**: Running it
+
+<span id="listing-4-4"></span>
+
+```sh
+$ ./main
+```
+"#);
+    assert_eq!(link_listings(&next_chapter, &numbering),
+               "See [the struct](#listing-4-3) (Listing 4-3).\n\n**Listing 5-1**\n\n\
+                <span id=\"listing-5-1\"></span>\n\n```rust\n```\n");
+    assert_eq!(numbering.count, 1);
+}
@@ -1,12 +1,21 @@
 //! Various Helper Functions
 
+pub mod adjust_header_ids;
 pub mod adjust_header_level;
 pub mod adjust_reference_names;
+pub mod anchors;
+pub mod check_links;
 pub mod convert_checkmarks;
 pub mod file;
+pub mod glossary;
+pub mod index_terms;
 pub mod line_breaks;
+pub mod link_notes;
+pub mod listings;
 pub mod normalize;
 pub mod normalize_code_blocks;
+pub mod raw_html;
 pub mod remove_emojis;
 pub mod remove_file_title;
 pub mod shell_pipe;
+pub mod yaml;
@@ -1,17 +1,58 @@
 use std::error::Error;
-use regex::Regex;
+use regex::{Regex, Captures};
 
 use helpers::normalize_code_blocks::*;
+use helpers::raw_html::split_code_spans;
 
-fn normalize_links(input: &str) -> Result<String, Box<Error>> {
-    let mut output = input.replace(r"../std", r"http://doc.rust-lang.org/std")
-                          .replace(r"../reference",
-                                   r"http://doc.rust-lang.org/reference")
-                          .replace(r"../rustc", r"http://doc.rust-lang.org/rustc")
-                          .replace(r"../syntax", r"http://doc.rust-lang.org/syntax")
-                          .replace(r"../book", r"http://doc.rust-lang.org/book")
-                          .replace(r"../adv-book", r"http://doc.rust-lang.org/adv-book")
-                          .replace(r"../core", r"http://doc.rust-lang.org/core");
+const CODE_BLOCK_TOGGLE: &'static str = "```";
+
+/// Relative link prefixes (like `../std`) and the URLs they should point to
+pub type LinkBases = Vec<(String, String)>;
+
+fn absolute_url(url: &str, link_bases: &LinkBases) -> Option<String> {
+    link_bases.iter()
+              .find(|&&(ref prefix, _)| {
+                  url.starts_with(&prefix[..]) &&
+                  url[prefix.len()..].chars().next().map_or(true, |c| "/.#".contains(c))
+              })
+              .map(|&(ref prefix, ref base)| format!("{}{}", base, &url[prefix.len()..]))
+}
+
+/// Make relative links to other documentation absolute. This only touches
+/// link destinations, not prose or code that happens to contain `../std`.
+fn normalize_link_bases(input: &str, link_bases: &LinkBases) -> Result<String, Box<Error>> {
+    let inline_link = Regex::new(r"\]\((?P<url>[^)\s]+)").unwrap();
+    let reference_def = Regex::new(r"^(?P<def>\[.+\]:\s+)(?P<url>\S+)").unwrap();
+
+    let mut in_code_block = false;
+
+    let output = input.lines()
+    .fold(String::new(), |initial, line| {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+        if in_code_block {
+            return initial + line + "\n";
+        }
+
+        let line = reference_def.replace(line, |caps: &Captures| {
+            let url = caps.name("url").unwrap();
+            format!("{}{}", caps.name("def").unwrap(),
+                            absolute_url(url, link_bases).unwrap_or(url.to_owned()))
+        });
+        let line = inline_link.replace_all(&line, |caps: &Captures| {
+            let url = caps.name("url").unwrap();
+            format!("]({}", absolute_url(url, link_bases).unwrap_or(url.to_owned()))
+        });
+
+        initial + &line + "\n"
+    });
+
+    Ok(output)
+}
+
+fn normalize_links(input: &str, link_bases: &LinkBases) -> Result<String, Box<Error>> {
+    let mut output = try!(normalize_link_bases(input, link_bases));
 
     let cross_section_link = Regex::new(r"]\((?P<file>[\w-_]+)\.html\)").unwrap();
     output = cross_section_link.replace_all(&output, r"](#sec--$file)");
@@ -21,29 +62,186 @@ fn normalize_links(input: &str) -> Result<String, Box<Error>> {
 
     let cross_subsection_link = Regex::new(r"]\((?P<file>[\w-_]+)\.html#(?P<subsection>[\w-_]+)\)")
                                     .unwrap();
-    output = cross_subsection_link.replace_all(&output, r"](#$subsection)");
+    output = cross_subsection_link.replace_all(&output, r"](#sec--$file--$subsection)");
 
     let cross_subsection_ref =
         Regex::new(r"(?m)^\[(?P<id>.+)\]:\s(?P<file>[^:^/]+)\.html#(?P<subsection>[\w-_]+)$")
             .unwrap();
-    output = cross_subsection_ref.replace_all(&output, r"[$id]: #$subsection");
+    output = cross_subsection_ref.replace_all(&output, r"[$id]: #sec--$file--$subsection");
 
     Ok(output)
 }
 
+/// Position of the `$` closing the inline math starting at `start`. This is
+/// the next `$` if it follows pandoc's rules: The content can't start or end
+/// with a space, and the closing `$` can't be followed by a digit. Unlike
+/// pandoc, we don't look any further, as in "$5 or $10, not $2$".
+fn closing_dollar(text: &[char], start: usize) -> Option<usize> {
+    match text.get(start + 1) {
+        Some(c) if !c.is_whitespace() && *c != '$' => {}
+        _ => return None,
+    }
+
+    (start + 1..text.len())
+        .find(|&i| text[i] == '$' && text[i - 1] != '\\')
+        .and_then(|i| {
+            if !text[i - 1].is_whitespace() && !text.get(i + 1).map_or(false, |c| c.is_digit(10)) {
+                Some(i)
+            } else {
+                None
+            }
+        })
+}
+
+/// Escape dollar signs that don't start or end inline math on this line, so
+/// pandoc doesn't pair them with one further down
+fn escape_stray_dollars(text: &str) -> String {
+    let text: Vec<char> = text.chars().collect();
+    let mut output = String::with_capacity(text.len());
+    let mut i = 0;
+
+    while i < text.len() {
+        match text[i] {
+            '\\' if i + 1 < text.len() => {
+                output.push(text[i]);
+                output.push(text[i + 1]);
+                i += 2;
+            }
+            '$' if text.get(i + 1) == Some(&'$') => {
+                // Display math
+                let end = (i + 2..text.len().saturating_sub(1))
+                              .find(|&j| text[j] == '$' && text[j + 1] == '$');
+                match end {
+                    Some(end) => {
+                        output.extend(&text[i..end + 2]);
+                        i = end + 2;
+                    }
+                    None => {
+                        output.push_str("\\$\\$");
+                        i += 2;
+                    }
+                }
+            }
+            '$' => {
+                match closing_dollar(&text, i) {
+                    Some(end) => {
+                        output.extend(&text[i..end + 1]);
+                        i = end + 1;
+                    }
+                    None => {
+                        output.push_str("\\$");
+                        i += 1;
+                    }
+                }
+            }
+            c => {
+                output.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    output
+}
+
 fn normalize_math(input: &str) -> Result<String, Box<Error>> {
     let superscript = Regex::new(r"(\d+)<sup>(\d+)</sup>").unwrap();
-    Ok(superscript.replace_all(&input, r"$1^$2^"))
+    let parenthesized = Regex::new(r"\\\((?P<math>.+?)\\\)").unwrap();
+
+    let mut in_code_block = false;
+    let mut in_code_span = false;
+    let mut output = String::with_capacity(input.len());
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+        if in_code_block || line.starts_with(CODE_BLOCK_TOGGLE) {
+            output.push_str(line);
+            output.push_str("\n");
+            continue;
+        }
+        if line.trim().is_empty() {
+            in_code_span = false;
+        }
+
+        for (part, is_code) in split_code_spans(line, &mut in_code_span) {
+            if is_code {
+                output.push_str(part);
+            } else {
+                let part = superscript.replace_all(part, r"$1^$2^");
+                // `\(x\)` is LaTeX's inline math, which pandoc writes as `$x$`
+                let part = parenthesized.replace_all(&part, |caps: &Captures| {
+                    format!("${}$", caps.name("math").unwrap().trim())
+                });
+                output.push_str(&escape_stray_dollars(&part));
+            }
+        }
+        output.push_str("\n");
+    }
+
+    Ok(output)
 }
 
 
-pub fn normalize(input: &str) -> Result<String, Box<Error>> {
+pub fn normalize(input: &str, hidden_code: HiddenCode, link_bases: &LinkBases)
+    -> Result<String, Box<Error>>
+{
     let mut output;
 
-    output = try!(break_code_blocks(&input, 87, "↳ "));
-    output = try!(normalize_code_start(&output));
-    output = try!(normalize_links(&output));
+    output = try!(normalize_code_start(&input, hidden_code));
+    output = try!(normalize_links(&output, link_bases));
     output = try!(normalize_math(&output));
 
     Ok(output)
 }
+
+#[test]
+fn link_bases() {
+    let bases = vec![("../std".to_owned(), "https://doc.rust-lang.org/std".to_owned()),
+                     ("../reference".to_owned(), "https://doc.rust-lang.org/reference".to_owned())];
+
+    let input = "Use [`Vec`](../std/vec/struct.Vec.html) (see [the reference][ref]), not
+`../std/vec` or ../std-like things.
+
+```text
+../std
+```
+
+[ref]: ../reference.html#attributes
+";
+
+    let output = "Use [`Vec`](https://doc.rust-lang.org/std/vec/struct.Vec.html) (see [the reference][ref]), not
+`../std/vec` or ../std-like things.
+
+```text
+../std
+```
+
+[ref]: https://doc.rust-lang.org/reference.html#attributes
+";
+
+    assert_eq!(normalize_link_bases(input, &bases).unwrap(), output);
+}
+
+#[test]
+fn math() {
+    let text = r"It costs $5 or $10, has $2^{64} - 1$ values and takes \( O(n \log n) \) steps.
+Also 2<sup>30</sup>, $$\sum_i i$$, `echo $HOME` and a `$ shell
+prompt $`.
+
+```sh
+$ echo $PATH
+```
+";
+
+    assert_eq!(normalize_math(text).unwrap(),
+               r"It costs \$5 or \$10, has $2^{64} - 1$ values and takes $O(n \log n)$ steps.
+Also 2^30^, $$\sum_i i$$, `echo $HOME` and a `$ shell
+prompt $`.
+
+```sh
+$ echo $PATH
+```
+");
+}
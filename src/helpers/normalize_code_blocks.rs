@@ -26,30 +26,128 @@ pub fn break_code_blocks(input: &str, max_len: usize, sep: &str) -> Result<Strin
 }
 
 
-pub fn normalize_code_start(input: &str) -> Result<String, Box<Error>> {
+/// What to do with lines rustdoc would hide (`# foo`) in Rust code blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HiddenCode {
+    /// Drop them, like the online version does before you click "expand"
+    Remove,
+    /// Keep them in separate, greyed out `.hidden-code` blocks
+    Show,
+}
+
+/// Apply rustdoc's rules to a line of Rust code: Returns `Some(code)` with the
+/// `# ` marker stripped (but indentation kept) if the line is hidden and `None`
+/// otherwise.
+fn hidden_line(line: &str) -> Option<String> {
+    let indent = line.len() - line.trim_left().len();
+    let trimmed = line.trim();
+
+    if trimmed == "#" {
+        Some(String::new())
+    } else if trimmed.starts_with("# ") || trimmed.starts_with("#\t") {
+        Some(format!("{}{}", &line[..indent], &line[indent + 2..]))
+    } else {
+        None
+    }
+}
+
+/// Rustdoc renders lines starting with `##` as visible lines starting with `#`.
+fn unescape_line(line: &str) -> String {
+    let indent = line.len() - line.trim_left().len();
+
+    if line[indent..].starts_with("##") {
+        format!("{}{}", &line[..indent], &line[indent + 1..])
+    } else {
+        line.to_owned()
+    }
+}
+
+const HIDDEN_CODE_START: &'static str = "\n```{.hidden-code}\n";
+const HIDDEN_CODE_END: &'static str = "```\n\n";
+
+pub fn normalize_code_start(input: &str, hidden_code: HiddenCode) -> Result<String, Box<Error>> {
     let rust_code_block_start = Regex::new(r"^```(.*)rust(.*)").unwrap();
-    let hidden_code = Regex::new(r"^(#\s.*|#$)").unwrap();
 
+    let mut output = String::new();
     let mut in_code_block = false;
+    // Only used when showing hidden code: Which kind of run of lines we are
+    // currently in (`None` means we still need to open a new block).
+    let mut current_run: Option<bool> = None;
 
-    let output = input.lines()
-    .fold(String::new(), |initial, line| {
-        if in_code_block && hidden_code.is_match(line) {
-            initial
+    for line in input.lines() {
+        if in_code_block && line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = false;
+            match (hidden_code, current_run) {
+                (HiddenCode::Remove, _) => {
+                    output.push_str(line);
+                    output.push_str("\n");
+                }
+                (HiddenCode::Show, Some(true)) => output.push_str(HIDDEN_CODE_END),
+                (HiddenCode::Show, Some(false)) => output.push_str("```\n"),
+                (HiddenCode::Show, None) => {}
+            }
+        } else if in_code_block {
+            let hidden = hidden_line(line);
+            let is_hidden = hidden.is_some();
+
+            match hidden_code {
+                HiddenCode::Remove if is_hidden => continue,
+                HiddenCode::Remove => {}
+                HiddenCode::Show if current_run != Some(is_hidden) => {
+                    match current_run {
+                        Some(true) => output.push_str(HIDDEN_CODE_END),
+                        Some(false) => output.push_str("```\n"),
+                        None => {}
+                    }
+                    output.push_str(if is_hidden { HIDDEN_CODE_START } else { "```rust\n" });
+                    current_run = Some(is_hidden);
+                }
+                HiddenCode::Show => {}
+            }
+
+            output.push_str(&hidden.unwrap_or_else(|| unescape_line(line)));
+            output.push_str("\n");
         } else if rust_code_block_start.is_match(line) {
             in_code_block = true;
-            initial + "```rust\n"
-        } else if line.starts_with(CODE_BLOCK_TOGGLE) {
-            in_code_block = false;
-            initial + line + "\n"
+            current_run = None;
+            if hidden_code == HiddenCode::Remove {
+                output.push_str("```rust\n");
+            }
         } else {
-            initial + line + "\n"
+            output.push_str(line);
+            output.push_str("\n");
         }
-    });
+    }
 
     Ok(output)
 }
 
+/// Grey out the `.hidden-code` blocks in the PDF, the other formats use CSS
+pub fn gray_hidden_code(input: &str) -> String {
+    let hidden_code_start = Regex::new(r"^```\{[^}]*\.hidden-code\b").unwrap();
+    let mut output = String::new();
+    let mut in_code_block = false;
+    let mut in_hidden_code = false;
+
+    for line in input.lines() {
+        if !in_code_block && hidden_code_start.is_match(line) {
+            output.push_str("\n\\begingroup\\color{gray}\n\n");
+            in_hidden_code = true;
+        }
+        output.push_str(line);
+        output.push_str("\n");
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            if in_code_block && in_hidden_code {
+                output.push_str("\n\\endgroup\n\n");
+                in_hidden_code = false;
+            }
+            in_code_block = !in_code_block;
+        }
+    }
+
+    output
+}
+
 #[test]
 fn code_block_breaking() {
     let long_code_block = "If we truly want a reference, we need the other option: ensure that our reference goes out of scope before we try to do the mutation. That looks like this:
@@ -63,15 +161,15 @@ We created an inner scope with an additional set of curly braces. `y` will go ou
     let code_block_broken_down = "If we truly want a reference, we need the other option: ensure that our reference goes out of scope before we try to do the mutation. That looks like this:
 
 ```text
-Whew! The Rust compiler gives quite detailed errors at times, and this is one o
-↳ f those times. As the error explains, while we made our binding mutable, we s
-↳ till cannot call `push`. This is because we already have a reference to an el
-↳ ement of the vector, `y`. Mutating something while another reference exists i
-↳ s dangerous, because we may invalidate the reference. In this speciffic case,
-↳  when we create the vector, we may have only allocated space for three elemen
-↳ ts. Adding a fourth would mean allocating a new chunk of memory for all those
-↳ e elements, copying the old values over, and updating the internal pointer to
-↳  that memory. That all works just fine.
+Whew! The Rust compiler gives quite detailed errors at times, and this is one
+↳ of those times. As the error explains, while we made our binding mutable, we
+↳ still cannot call `push`. This is because we already have a reference to an
+↳ element of the vector, `y`. Mutating something while another reference exists
+↳ is dangerous, because we may invalidate the reference. In this speciffic
+↳ case, when we create the vector, we may have only allocated space for three
+↳ elements. Adding a fourth would mean allocating a new chunk of memory for all
+↳ thosee elements, copying the old values over, and updating the internal
+↳ pointer to that memory. That all works just fine.
 ```
 
 We created an inner scope with an additional set of curly braces. `y` will go out of scope before we call `push()`, and so we’re all good.
@@ -130,7 +228,54 @@ let x = true;
 ```
 ";
 
-    let cleaned = normalize_code_start(code_blocks).unwrap();
+    let cleaned = normalize_code_start(code_blocks, HiddenCode::Remove).unwrap();
 
     assert_eq!(cleaned, code_blocks_clean);
 }
+
+#[test]
+fn hidden_code_lines() {
+    let code_block = "```rust
+# fn main() {
+    # let x = 5;
+#
+## [derive(Debug)]
+#[derive(Debug)]
+struct Foo;
+# }
+```
+";
+
+    let removed = "```rust
+# [derive(Debug)]
+#[derive(Debug)]
+struct Foo;
+```
+";
+
+    let shown = "
+```{.hidden-code}
+fn main() {
+    let x = 5;
+
+```
+
+```rust
+# [derive(Debug)]
+#[derive(Debug)]
+struct Foo;
+```
+
+```{.hidden-code}
+}
+```
+
+";
+
+    assert_eq!(normalize_code_start(code_block, HiddenCode::Remove).unwrap(), removed);
+    assert_eq!(normalize_code_start(code_block, HiddenCode::Show).unwrap(), shown);
+
+    assert_eq!(gray_hidden_code("```{#code-x .hidden-code}\nfn main() {\n```\n```rust\n}\n```\n"),
+               "\n\\begingroup\\color{gray}\n\n```{#code-x .hidden-code}\nfn main() {\n```\n\n\\endgroup\n\n\
+                ```rust\n}\n```\n");
+}
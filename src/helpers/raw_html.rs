@@ -0,0 +1,288 @@
+//! Translate raw HTML for writers that drop it
+//!
+//! Pandoc only keeps raw HTML in HTML based formats, so e.g. `<kbd>` or
+//! `<table>` would vanish from the PDF. This turns the tags we know into
+//! Markdown and reports the ones we don't.
+
+use regex::{Regex, Captures};
+
+const CODE_BLOCK_TOGGLE: &'static str = "```";
+
+/// Raw HTML that is left after translating, found in the section with the
+/// given ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownHtml {
+    pub section: String,
+    pub html: String,
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&")
+}
+
+fn attribute(attrs: &str, name: &str) -> Option<String> {
+    let pattern = Regex::new(&format!(r#"\b{}\s*=\s*["']([^"']*)["']"#, name)).unwrap();
+    pattern.captures(attrs).and_then(|caps| caps.at(1)).map(str::to_owned)
+}
+
+/// Translate inline tags, innermost first. `in_table` is for pipe table
+/// rows, which can't contain line breaks.
+fn translate_inline(line: &str, in_table: bool) -> String {
+    let paired = Regex::new(r"(?i)<(?P<tag>sup|sub|kbd|em|i|strong|b|code|span|a)(?P<attrs>\s[^<>]*)?>(?P<content>(`[^`]*`|[^<`])*)</(?P<end>[a-z]+)>").unwrap();
+    let line_break = Regex::new(r"(?i)<br\s*/?>").unwrap();
+    let image = Regex::new(r"(?i)<img(?P<attrs>\s[^<>]*)/?>").unwrap();
+    let anchor = Regex::new(r#"(?i)<(span|a)\s+(id|name)=["'](?P<id>[^"']+)["']\s*>\s*</(span|a)>"#).unwrap();
+
+    let mut line = image.replace_all(line, |caps: &Captures| {
+        let attrs = caps.name("attrs").unwrap_or("");
+        format!("![{}]({})",
+                attribute(attrs, "alt").unwrap_or(String::new()),
+                attribute(attrs, "src").unwrap_or(String::new()))
+    });
+
+    // Empty anchors become LaTeX targets
+    line = anchor.replace_all(&line, r"\hypertarget{$id}{}");
+
+    line = line_break.replace_all(&line, if in_table { " " } else { "\\\n" });
+
+    loop {
+        let translated = paired.replace_all(&line, |caps: &Captures| {
+            let tag = caps.name("tag").unwrap().to_lowercase();
+            let content = caps.name("content").unwrap_or("");
+            if caps.name("end").unwrap().to_lowercase() != tag {
+                return caps.at(0).unwrap().to_owned();
+            }
+
+            match &tag[..] {
+                "sup" => format!("^{}^", content.replace(" ", "\\ ")),
+                "sub" => format!("~{}~", content.replace(" ", "\\ ")),
+                "kbd" | "code" => format!("`{}`", unescape(content)),
+                "em" | "i" => format!("*{}*", content),
+                "strong" | "b" => format!("**{}**", content),
+                "a" => {
+                    match attribute(caps.name("attrs").unwrap_or(""), "href") {
+                        Some(href) => format!("[{}]({})", content, href),
+                        None => content.to_owned(),
+                    }
+                }
+                _ => content.to_owned(),
+            }
+        });
+
+        if translated == line {
+            return line;
+        }
+        line = translated;
+    }
+}
+
+/// A HTML table as a pipe table
+fn translate_table(html: &str) -> String {
+    let row = Regex::new(r"(?is)<tr[^>]*>(?P<cells>.*?)</tr>").unwrap();
+    let cell = Regex::new(r"(?is)<t[hd][^>]*>(?P<content>.*?)</t[hd]>").unwrap();
+    let whitespace = Regex::new(r"\s+").unwrap();
+
+    let rows: Vec<Vec<String>> = row.captures_iter(html)
+        .map(|row| {
+            cell.captures_iter(row.name("cells").unwrap())
+                .map(|cell| {
+                    let content = whitespace.replace_all(cell.name("content").unwrap().trim(), " ");
+                    translate_inline(&content, true).replace("|", "\\|")
+                })
+                .collect()
+        })
+        .filter(|cells: &Vec<String>| !cells.is_empty())
+        .collect();
+
+    let columns = rows.iter().map(|cells| cells.len()).max().unwrap_or(0);
+    if columns == 0 {
+        return String::new();
+    }
+
+    let format_row = |cells: &[String]| -> String {
+        let mut cells = cells.to_vec();
+        cells.resize(columns, String::new());
+        format!("| {} |\n", cells.join(" | "))
+    };
+
+    // Pipe tables need a header, the first row is as good as any
+    let mut output = format_row(&rows[0]);
+    output.push_str(&format!("|{}\n", "---|".repeat(columns)));
+    for cells in &rows[1..] {
+        output.push_str(&format_row(cells));
+    }
+    output
+}
+
+/// Split a line into text and code spans, `in_code_span` tells whether a
+/// code span from the line before continues
+pub fn split_code_spans<'a>(line: &'a str, in_code_span: &mut bool) -> Vec<(&'a str, bool)> {
+    let mut parts = vec![];
+    let mut rest = line;
+
+    while !rest.is_empty() {
+        match rest.find('`') {
+            Some(index) => {
+                // The backtick belongs to the code span
+                let end = if *in_code_span { index + 1 } else { index };
+                parts.push((&rest[..end], *in_code_span));
+                rest = &rest[end..];
+                if !*in_code_span {
+                    parts.push((&rest[..1], true));
+                    rest = &rest[1..];
+                }
+                *in_code_span = !*in_code_span;
+            }
+            None => {
+                parts.push((rest, *in_code_span));
+                rest = "";
+            }
+        }
+    }
+
+    parts
+}
+
+/// Translate the raw HTML in `input` to Markdown and return the tags we don't
+/// know
+pub fn translate_html(input: &str) -> (String, Vec<UnknownHtml>) {
+    let heading = Regex::new(r"^#+\s.*\{#(?P<id>[^\s}]+)[^}]*\}\s*$").unwrap();
+    let inline_code = Regex::new(r"`+[^`]*`+").unwrap();
+    let div = Regex::new(r"(?i)^\s*</?div(\s[^<>]*)?>\s*$").unwrap();
+    let comment = Regex::new(r"<!--.*?-->").unwrap();
+    let tag = Regex::new(r"</?[a-zA-Z][a-zA-Z0-9-]*(\s[^<>]*)?/?>").unwrap();
+
+    let mut output = String::with_capacity(input.len());
+    let mut unknown = vec![];
+    let mut section = String::new();
+    let mut table: Option<String> = None;
+    let mut in_code_block = false;
+    let mut in_code_span = false;
+
+    for line in input.lines() {
+        if line.starts_with(CODE_BLOCK_TOGGLE) {
+            in_code_block = !in_code_block;
+        }
+        if in_code_block || line.starts_with(CODE_BLOCK_TOGGLE) {
+            output.push_str(line);
+            output.push_str("\n");
+            continue;
+        }
+
+        if let Some(caps) = heading.captures(line) {
+            section = caps.name("id").unwrap().to_owned();
+        }
+
+        if table.is_some() || line.trim_left().to_lowercase().starts_with("<table") {
+            let mut html = table.take().unwrap_or(String::new());
+            html.push_str(line);
+            html.push_str("\n");
+            if line.to_lowercase().contains("</table>") {
+                output.push_str(&translate_table(&html));
+            } else {
+                table = Some(html);
+            }
+            continue;
+        }
+
+        if div.is_match(line) {
+            output.push_str("\n");
+            continue;
+        }
+
+        // Leave code spans alone, they are not HTML. They can span lines but
+        // not paragraphs.
+        if line.trim().is_empty() {
+            in_code_span = false;
+        }
+        let mut translated = String::new();
+        let mut unknown_in_line = String::new();
+        for (part, is_code) in split_code_spans(line, &mut in_code_span) {
+            if is_code {
+                translated.push_str(part);
+            } else {
+                let part = translate_inline(part, line.starts_with('|'));
+                translated.push_str(&part);
+                // Translated tags can become code spans
+                unknown_in_line.push_str(&inline_code.replace_all(&comment.replace_all(&part, ""), ""));
+            }
+        }
+
+        for (start, end) in tag.find_iter(&unknown_in_line) {
+            unknown.push(UnknownHtml {
+                section: section.clone(),
+                html: unknown_in_line[start..end].to_owned(),
+            });
+        }
+
+        output.push_str(&translated);
+        output.push_str("\n");
+    }
+
+    if let Some(html) = table {
+        output.push_str(&html);
+    }
+
+    (output, unknown)
+}
+
+#[test]
+fn html_translation() {
+    let input = r#"## Keys {#sec--keys}
+
+Press <kbd>Ctrl</kbd>+<kbd>C</kbd>, it's 2<sup>n - 1</sup><br>in <em><code>Vec&lt;T&gt;</code></em>
+and <a href="https://rust-lang.org">Rust</a>, but not `<b>this</b>`.
+
+<div class="note">
+<table>
+  <tr><th>Key</th><th>Action</th></tr>
+  <tr><td><kbd>q</kbd></td><td>Quit | exit</td></tr>
+</table>
+</div>
+
+```html
+<blink>code</blink>
+```
+
+A <blink>tag</blink><!-- and a comment -->, `a code
+<span>` across lines.
+"#;
+
+    let (output, unknown) = translate_html(input);
+
+    assert_eq!(output, r#"## Keys {#sec--keys}
+
+Press `Ctrl`+`C`, it's 2^n\ -\ 1^\
+in *`Vec<T>`*
+and [Rust](https://rust-lang.org), but not `<b>this</b>`.
+
+
+| Key | Action |
+|---|---|
+| `q` | Quit \| exit |
+
+
+```html
+<blink>code</blink>
+```
+
+A <blink>tag</blink><!-- and a comment -->, `a code
+<span>` across lines.
+"#);
+    assert_eq!(unknown, vec![
+        UnknownHtml { section: "sec--keys".into(), html: "<blink>".into() },
+        UnknownHtml { section: "sec--keys".into(), html: "</blink>".into() },
+    ]);
+}
+
+#[test]
+fn anchor_targets() {
+    use helpers::anchors::anchors_to_identifiers;
+
+    let chapter = "See [here](#paragraph).\n\n<span id=\"paragraph\"></span>\n\nSome text.\n";
+    let (output, unknown) = translate_html(&anchors_to_identifiers(chapter));
+
+    assert_eq!(output, "See [here](#paragraph).\n\n\\hypertarget{paragraph}{}\n\nSome text.\n");
+    assert_eq!(output.matches("\\hypertarget").count(), 1);
+    assert!(unknown.is_empty());
+}
@@ -1,6 +1,16 @@
 use std::error::Error;
 use regex::Regex;
 
+const FILE_TITLE: &'static str = r"^%\s(.+)\n";
+
 pub fn remove_file_title(input: &str) -> Result<String, Box<Error>> {
-    Ok(Regex::new(r"^%\s(.+)\n").unwrap().replace(input, ""))
+    Ok(Regex::new(FILE_TITLE).unwrap().replace(input, ""))
+}
+
+pub fn get_file_title(input: &str) -> Option<String> {
+    Regex::new(FILE_TITLE)
+        .unwrap()
+        .captures(input)
+        .and_then(|caps| caps.at(1))
+        .map(|title| title.trim().to_owned())
 }
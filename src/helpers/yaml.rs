@@ -0,0 +1,396 @@
+//! Read the YAML meta data of a book
+//!
+//! This only understands the subset of YAML meta data files use: Block
+//! mappings and sequences, quoted, plain and block (`|` and `>`) scalars,
+//! booleans, integers, flow sequences like `[a, b]` and comments.
+
+use std::error::Error;
+use std::collections::BTreeMap;
+use rustc_serialize::json::Json;
+
+type Line = (usize, String);
+
+/// Parse the first YAML document in `input`. Pandoc style documents ending in
+/// `...` are fine, as is Markdown following the YAML block.
+pub fn parse_yaml(input: &str) -> Result<Json, Box<Error>> {
+    let input: Vec<&str> = input.lines().collect();
+    let mut lines: Vec<Line> = vec![];
+    let mut started = false;
+    let mut next = 0;
+
+    while next < input.len() {
+        let line = input[next];
+        next += 1;
+        let trimmed = line.trim();
+        if trimmed == "---" {
+            if started || !lines.is_empty() {
+                break;
+            }
+            started = true;
+            continue;
+        }
+        if trimmed == "..." {
+            break;
+        }
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let indent = indentation(line);
+        let trimmed = strip_comment(trimmed);
+
+        // Block scalars become a double-quoted scalar on the line of their key
+        if let Some((head, indicator)) = block_scalar(trimmed) {
+            let start = next;
+            while next < input.len() &&
+                  (input[next].trim().is_empty() || indentation(input[next]) > indent) {
+                next += 1;
+            }
+            let text = block_scalar_text(&input[start..next], indicator);
+            lines.push((indent, format!("{} {}", head, quote(&text))));
+            continue;
+        }
+
+        lines.push((indent, trimmed.to_owned()));
+    }
+
+    let mut pos = 0;
+    let indent = lines.first().map_or(0, |&(i, _)| i);
+    let value = try!(parse_block(&mut lines, &mut pos, indent));
+
+    if pos < lines.len() {
+        return Err(From::from(format!("Unexpected indentation in YAML line `{}`", lines[pos].1)));
+    }
+
+    Ok(value)
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_left().len()
+}
+
+/// `line` without a `# comment` at its end
+fn strip_comment(line: &str) -> &str {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            // Quotes only start a scalar, apostrophes in plain ones don't count
+            None if (c == '"' || c == '\'') && " [,:-".contains(previous) => quote = Some(c),
+            None if c == '#' && previous.is_whitespace() => return line[..index].trim_right(),
+            None => {}
+        }
+        previous = c;
+    }
+
+    line
+}
+
+/// Split `key: |` and `- >` lines into the key (or dash) and the block scalar
+/// indicator
+fn block_scalar(line: &str) -> Option<(&str, &str)> {
+    ["|", "|-", "|+", ">", ">-", ">+"]
+        .iter()
+        .filter(|indicator| line.ends_with(*indicator))
+        .map(|indicator| (line[..line.len() - indicator.len()].trim_right(), *indicator))
+        .find(|&(head, _)| head == "-" || head.ends_with(':'))
+}
+
+/// The value of a block scalar with the given indicator and (indented) lines
+fn block_scalar_text(lines: &[&str], indicator: &str) -> String {
+    let indent = lines.iter().find(|line| !line.trim().is_empty()).map_or(0, |line| indentation(line));
+    let lines: Vec<&str> = lines.iter()
+                                .map(|line| if line.len() > indent { &line[indent..] } else { "" })
+                                .collect();
+
+    let mut text = String::new();
+    if indicator.starts_with('|') {
+        for line in lines {
+            text.push_str(line);
+            text.push('\n');
+        }
+    } else {
+        // Folded: single line breaks become spaces, except before and after
+        // more indented lines
+        let is_text = |line: &str| !line.is_empty() && !line.starts_with(' ');
+        let mut previous: Option<&str> = None;
+        for line in lines {
+            match previous {
+                Some(p) if is_text(p) && is_text(line) => text.push(' '),
+                Some(p) if !p.is_empty() || line.is_empty() => text.push('\n'),
+                _ => {}
+            }
+            text.push_str(line);
+            previous = Some(line);
+        }
+        text.push('\n');
+    }
+
+    // Chomping: `-` strips the final line breaks, `+` keeps them all
+    if indicator.ends_with('+') {
+        text
+    } else {
+        let content = text.trim_right_matches('\n');
+        if indicator.ends_with('-') || content.is_empty() {
+            content.to_owned()
+        } else {
+            format!("{}\n", content)
+        }
+    }
+}
+
+/// `text` as a double-quoted scalar
+fn quote(text: &str) -> String {
+    format!("\"{}\"",
+            text.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n").replace("\t", "\\t"))
+}
+
+/// The content of a double-quoted scalar, with its escapes resolved
+fn unescape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some(c) if c == '"' || c == '\\' || c == '/' => output.push(c),
+            Some(c) => {
+                output.push('\\');
+                output.push(c);
+            }
+            None => output.push('\\'),
+        }
+    }
+
+    output
+}
+
+fn is_sequence_item(line: &str) -> bool {
+    line == "-" || line.starts_with("- ")
+}
+
+/// Split `key: value` lines, returns `None` for lines that are no mapping entry.
+fn split_key(line: &str) -> Option<(&str, &str)> {
+    if line.ends_with(':') {
+        return Some((&line[..line.len() - 1], ""));
+    }
+
+    line.find(": ").map(|index| (&line[..index], line[index + 2..].trim()))
+}
+
+fn parse_block(lines: &mut Vec<Line>, pos: &mut usize, indent: usize) -> Result<Json, Box<Error>> {
+    if *pos >= lines.len() {
+        return Ok(Json::Null);
+    }
+
+    if is_sequence_item(&lines[*pos].1) {
+        parse_sequence(lines, pos, indent)
+    } else {
+        parse_mapping(lines, pos, indent)
+    }
+}
+
+fn parse_sequence(lines: &mut Vec<Line>, pos: &mut usize, indent: usize) -> Result<Json, Box<Error>> {
+    let mut items = vec![];
+
+    while *pos < lines.len() && lines[*pos].0 == indent && is_sequence_item(&lines[*pos].1) {
+        let item = lines[*pos].1[1..].trim().to_owned();
+
+        if item.is_empty() {
+            *pos += 1;
+            let child_indent = lines.get(*pos).map_or(0, |&(i, _)| i);
+            if child_indent > indent {
+                items.push(try!(parse_block(lines, pos, child_indent)));
+            } else {
+                items.push(Json::Null);
+            }
+        } else if split_key(&item).is_some() || is_sequence_item(&item) {
+            // A nested collection starting on the same line as the `- `:
+            // Pretend it started on its own line.
+            let child_indent = indent + lines[*pos].1.len() - item.len();
+            lines[*pos] = (child_indent, item);
+            items.push(try!(parse_block(lines, pos, child_indent)));
+        } else {
+            items.push(parse_scalar(&item));
+            *pos += 1;
+        }
+    }
+
+    Ok(Json::Array(items))
+}
+
+fn parse_mapping(lines: &mut Vec<Line>, pos: &mut usize, indent: usize) -> Result<Json, Box<Error>> {
+    let mut map = BTreeMap::new();
+
+    while *pos < lines.len() && lines[*pos].0 == indent && !is_sequence_item(&lines[*pos].1) {
+        let (key, value) = try!(split_key(&lines[*pos].1).ok_or_else(|| {
+            format!("Expected `key: value` in YAML line `{}`", lines[*pos].1)
+        }));
+        let (key, value) = (unquote(key), if value.is_empty() { None } else { Some(parse_scalar(value)) });
+        *pos += 1;
+
+        if let Some(value) = value {
+            map.insert(key, value);
+            continue;
+        }
+
+        // Nested blocks are indented, but sequences may also start at the
+        // same indentation as their key.
+        let value = match lines.get(*pos).cloned() {
+            Some((child_indent, _)) if child_indent > indent => {
+                try!(parse_block(lines, pos, child_indent))
+            }
+            Some((child_indent, line)) if child_indent == indent && is_sequence_item(&line) => {
+                try!(parse_sequence(lines, pos, indent))
+            }
+            _ => Json::Null,
+        };
+        map.insert(key, value);
+    }
+
+    Ok(Json::Object(map))
+}
+
+fn unquote(input: &str) -> String {
+    let input = input.trim();
+
+    if input.len() >= 2 && input.starts_with('"') && input.ends_with('"') {
+        unescape(&input[1..input.len() - 1])
+    } else if input.len() >= 2 && input.starts_with('\'') && input.ends_with('\'') {
+        input[1..input.len() - 1].replace("''", "'")
+    } else {
+        input.to_owned()
+    }
+}
+
+/// Split the items of a flow sequence at commas outside of quotes
+fn split_flow_sequence(input: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let mut quote = None;
+    let mut start = 0;
+
+    for (index, c) in input.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, ',') => {
+                items.push(&input[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&input[start..]);
+
+    items.into_iter().map(str::trim).filter(|item| !item.is_empty()).collect()
+}
+
+fn parse_scalar(input: &str) -> Json {
+    let input = input.trim();
+
+    if input.starts_with('[') && input.ends_with(']') {
+        return Json::Array(split_flow_sequence(&input[1..input.len() - 1])
+                               .into_iter()
+                               .map(parse_scalar)
+                               .collect());
+    }
+
+    match input {
+        "true" | "yes" => return Json::Boolean(true),
+        "false" | "no" => return Json::Boolean(false),
+        "null" | "~" => return Json::Null,
+        _ => {}
+    }
+
+    if let Ok(number) = input.parse::<i64>() {
+        return Json::I64(number);
+    }
+
+    Json::String(unquote(input))
+}
+
+#[test]
+fn parse_meta_data() {
+    let meta = r#"---
+title: "The Rust Programming Language"
+date: {release_date}
+links-as-notes: true
+toc-depth: 2
+keywords: [Rust, "systems, programming"]
+front-matter:
+  - file: README.md
+    title: 'Introduction'
+  - file: foreword.md
+    numbered: false
+link-bases:
+  ../std: https://doc.rust-lang.org/std
+...
+
+# Markdown: Not part of the meta data
+"#;
+
+    let json = parse_yaml(meta).unwrap();
+
+    assert_eq!(json.find("title").and_then(Json::as_string),
+               Some("The Rust Programming Language"));
+    assert_eq!(json.find("date").and_then(Json::as_string), Some("{release_date}"));
+    assert_eq!(json.find("links-as-notes").and_then(Json::as_boolean), Some(true));
+    assert_eq!(json.find("toc-depth").and_then(Json::as_i64), Some(2));
+    assert_eq!(json.find("keywords"),
+               Some(&Json::Array(vec![Json::String("Rust".into()),
+                                      Json::String("systems, programming".into())])));
+
+    let front_matter = json.find("front-matter").and_then(Json::as_array).unwrap();
+    assert_eq!(front_matter.len(), 2);
+    assert_eq!(front_matter[0].find("title").and_then(Json::as_string), Some("Introduction"));
+    assert_eq!(front_matter[1].find("numbered").and_then(Json::as_boolean), Some(false));
+
+    assert_eq!(json.find_path(&["link-bases", "../std"]).and_then(Json::as_string),
+               Some("https://doc.rust-lang.org/std"));
+    assert_eq!(json.find("Markdown"), None);
+}
+
+#[test]
+fn block_scalars_and_comments() {
+    let meta = r#"---
+title: A # comment
+homepage: https://doc.rust-lang.org/book/#ownership
+subtitle: "Not # a comment"
+abstract: |
+  line one
+    indented # kept
+
+  line three
+description: >-
+  Folded
+  into one line
+keywords:
+  - |-
+    first
+  - second # comment
+...
+"#;
+
+    let json = parse_yaml(meta).unwrap();
+
+    assert_eq!(json.find("title").and_then(Json::as_string), Some("A"));
+    assert_eq!(json.find("homepage").and_then(Json::as_string),
+               Some("https://doc.rust-lang.org/book/#ownership"));
+    assert_eq!(json.find("subtitle").and_then(Json::as_string), Some("Not # a comment"));
+    assert_eq!(json.find("abstract").and_then(Json::as_string),
+               Some("line one\n  indented # kept\n\nline three\n"));
+    assert_eq!(json.find("description").and_then(Json::as_string), Some("Folded into one line"));
+    assert_eq!(json.find("keywords"),
+               Some(&Json::Array(vec![Json::String("first".into()), Json::String("second".into())])));
+}
@@ -12,8 +12,15 @@
 extern crate regex;
 extern crate docopt;
 extern crate rustc_serialize;
+extern crate unicode_segmentation;
+extern crate unicode_width;
+extern crate zip;
+extern crate flate2;
+extern crate sha1;
+extern crate sha2;
 
 use std::path::Path;
+use std::process;
 use docopt::Docopt;
 
 pub mod helpers;
@@ -23,19 +30,51 @@ static USAGE: &'static str = r#"
 Compile Rustbook to EBook formats.
 
 Usage:
-  compile-trpl [--prefix=<prefix>] [--source=<directory>] [--meta=<meta_file>]
+  compile-trpl [--prefix=<prefix>] [--source=<directory>] [--meta=<meta_file>] [--show-hidden-code=<formats>] [--pdf=<profiles>] [--index-template=<file>] [--keep=<n>] [--since=<date>] [--kindle=<formats>] [--kindle-converter=<binary>] [--strict]
+  compile-trpl check [--source=<directory>] [--meta=<meta_file>] [--allow=<allowlist>]
+  compile-trpl prune [--keep=<n>] [--since=<date>] [--archive] [--dry-run] [--index-template=<file>]
 
 Options:
   --prefix=<prefix>     Prefix/short name of your book, e.g. "trpl" or "nomicon".
   --source=<directory>  Directory containing the git book files, especially SUMMARY.md and README.md.
   --meta=<meta_file>    Meta data of your book, needs to contain `date: {release_date}`.
+  --show-hidden-code=<formats>  Comma separated list of formats (md, html, epub, pdf or all)
+                        that show the hidden lines of Rust code examples greyed out.
+  --pdf=<profiles>      Comma separated list of page profiles to render PDFs for (a4, letter,
+                        a5, b5, 6x9 or ereader), `a4,letter` by default.
+  --kindle=<formats>    Comma separated list of Kindle formats (mobi, azw3) to convert the EPUB to.
+  --kindle-converter=<binary>  Program converting the EPUB, Calibre's `ebook-convert` by default
+                        or `kindlegen` (MOBI only).
+  --strict              Fail if checking the EPUB finds errors.
+  --index-template=<file>  HTML template of the download page, `lib/index_template.html`
+                        by default.
+  --allow=<allowlist>   File with URL prefixes (one per line) that don't need to be listed
+                        for auditing.
+  --keep=<n>            Only list (or with `prune`, keep) the n newest releases of each book.
+  --since=<date>        Only list (or keep) releases from this date on, e.g. 2016-01-01.
+                        Releases matching either --keep or --since are kept.
+  --archive             Move old releases to `dist/archive/` instead of deleting them.
+  --dry-run             Only list the files `prune` would delete or move.
 "#;
 
 #[derive(Debug, RustcDecodable)]
 struct Args {
+    cmd_check: bool,
+    cmd_prune: bool,
     flag_prefix: Option<String>,
     flag_source: Option<String>,
     flag_meta: Option<String>,
+    flag_show_hidden_code: Option<String>,
+    flag_pdf: Option<String>,
+    flag_allow: Option<String>,
+    flag_index_template: Option<String>,
+    flag_keep: Option<usize>,
+    flag_since: Option<String>,
+    flag_archive: bool,
+    flag_dry_run: bool,
+    flag_kindle: Option<String>,
+    flag_kindle_converter: Option<String>,
+    flag_strict: bool,
 }
 
 fn main() {
@@ -47,9 +86,52 @@ fn main() {
     let source = args.flag_source.unwrap_or("trpl".to_owned());
     let meta = args.flag_meta.unwrap_or("trpl_meta.yml".to_owned());
 
-    convert_book::render_book(&prefix, &Path::new(&source), &meta).unwrap();
+    if args.cmd_check {
+        let allowlist = args.flag_allow.as_ref().map(|a| &a[..]);
+        if !convert_book::check::check_book(&Path::new(&source), &meta, allowlist).unwrap() {
+            process::exit(1);
+        }
+        return;
+    }
 
-    let index = convert_book::index::render_index("dist/").unwrap();
-    helpers::file::write_string_to_file(&index, "dist/index.html").unwrap();
-    println!("[✓] {}", "Index");
+    let retention = convert_book::prune::Retention::new(args.flag_keep, args.flag_since)
+        .unwrap_or_else(|e| {
+            println!("[✗] {}", e);
+            process::exit(1);
+        });
+
+    let index_template = args.flag_index_template.unwrap_or("lib/index_template.html".to_owned());
+    let template = helpers::file::get_file_content(&index_template).unwrap();
+
+    if args.cmd_prune {
+        convert_book::prune::prune("dist/", &retention, args.flag_archive, args.flag_dry_run).unwrap();
+        // The pages would still list the removed files
+        if !args.flag_dry_run {
+            convert_book::render_downloads("dist/", &template, &retention).unwrap();
+        }
+        return;
+    }
+
+    let show_hidden_code: Vec<String> = args.flag_show_hidden_code
+        .map(|formats| formats.split(',').map(|f| f.trim().to_owned()).collect())
+        .unwrap_or(vec![]);
+
+    let pdf_profiles = args.flag_pdf.unwrap_or(convert_book::pdf::DEFAULT_PAGE_PROFILES.to_owned());
+    let pdf_profiles = convert_book::pdf::page_profiles(&pdf_profiles).unwrap();
+
+    let kindle_converter = args.flag_kindle_converter.unwrap_or("ebook-convert".to_owned());
+    let kindle = args.flag_kindle.map(|formats| {
+        convert_book::kindle::Kindle::new(&kindle_converter, &formats).unwrap()
+    });
+
+    convert_book::render_book(&prefix,
+                              &Path::new(&source),
+                              &meta,
+                              &show_hidden_code,
+                              &pdf_profiles,
+                              kindle.as_ref(),
+                              args.flag_strict)
+        .unwrap();
+
+    convert_book::render_downloads("dist/", &template, &retention).unwrap();
 }